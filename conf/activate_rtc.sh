@@ -1,2 +0,0 @@
-#!/bin/bash
-echo ds1307 0x68 > /sys/class/i2c-adapter/i2c-1/new_device
\ No newline at end of file
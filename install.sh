@@ -1,6 +1,38 @@
 #!/usr/bin/env bash
-echo "deb http://apt.peachcloud.org/ buster main" > /etc/apt/sources.list.d/peach.list
-wget -O - http://apt.peachcloud.org/pubkey.gpg | sudo apt-key add -
+set -e
+
+# Fingerprint of the PeachCloud repository signing key,
+# which must match KEY_FINGERPRINT in src/setup_peach_deb.rs
+PEACHCLOUD_KEY_FINGERPRINT=0000000000000000000000000000000000000000
+
+# download the key to a private temporary directory and only install it if its fingerprint matches
+KEY_DIR=$(mktemp -d)
+trap 'rm -rf "$KEY_DIR"' EXIT
+wget -qO "$KEY_DIR/pubkey.gpg" https://apt.peachcloud.org/pubkey.gpg
+# the fingerprint of each primary key is the tenth field of the "fpr" record following its "pub" record
+FINGERPRINTS=$(gpg --batch --show-keys --with-colons "$KEY_DIR/pubkey.gpg" \
+    | awk -F: '$1 == "pub" { primary = 1 } $1 == "fpr" && primary { print $10; primary = 0 }' \
+    | paste -sd ' ')
+if [ "$FINGERPRINTS" != "$PEACHCLOUD_KEY_FINGERPRINT" ]; then
+    echo "PeachCloud repository key has fingerprint $FINGERPRINTS, expected $PEACHCLOUD_KEY_FINGERPRINT" >&2
+    exit 1
+fi
+gpg --dearmor < "$KEY_DIR/pubkey.gpg" > /usr/share/keyrings/peachcloud.gpg
+
+# write the same deb822 source which peach-config writes, for the Debian release of the device,
+# falling back to the newest distribution of the repository as peach-config does
+CODENAME=$(. /etc/os-release && echo "$VERSION_CODENAME")
+case "$CODENAME" in
+    buster|bullseye|bookworm) ;;
+    *) CODENAME=bookworm ;;
+esac
+cat > /etc/apt/sources.list.d/peachcloud.sources <<EOF
+Types: deb
+URIs: http://apt.peachcloud.org/
+Suites: $CODENAME
+Components: main
+Signed-By: /usr/share/keyrings/peachcloud.gpg
+EOF
 apt-get update
 apt-get install -y peach-config
-RUST_LOG=info peach-config setup -i -n -d
\ No newline at end of file
+RUST_LOG=info peach-config setup -i -n -d
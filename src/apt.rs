@@ -0,0 +1,574 @@
+use log::{info, warn};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::process::Output;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::dpkg::read_package_status;
+use crate::error::{CmdParseOutputError, PeachConfigError};
+use crate::settings::load_settings;
+use crate::utils::cmd_with_env;
+
+// Seconds to wait for another process to release the dpkg lock, unless configured otherwise
+pub const DEFAULT_LOCK_TIMEOUT: u64 = 600;
+
+// Time between attempts to run a command while the dpkg lock is held
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// Time between progress messages while waiting for the dpkg lock
+const LOCK_PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+// Environment of every apt and dpkg command, so that no package can prompt for input
+const APT_ENV: [(&str, &str); 1] = [("DEBIAN_FRONTEND", "noninteractive")];
+
+// Keep the installed version of a changed configuration file without prompting,
+// unless the package marks the new version as the default
+const DPKG_CONF_OPTIONS: [&str; 4] = [
+    "-o",
+    "Dpkg::Options::=--force-confdef",
+    "-o",
+    "Dpkg::Options::=--force-confold",
+];
+
+// Messages printed by apt and dpkg when another process holds the dpkg lock
+const LOCK_MESSAGES: [&str; 4] = [
+    "Could not get lock",
+    "Unable to acquire the dpkg frontend lock",
+    "Unable to lock the administration directory",
+    "dpkg status database is locked by another process",
+];
+
+// Message printed by apt when a previous run of dpkg was interrupted
+const INTERRUPTED_MESSAGE: &str = "dpkg was interrupted";
+
+// Messages which classify the failures of apt, checked in order
+const NOT_FOUND_MESSAGES: [&str; 3] = [
+    "Unable to locate package",
+    "was not found",
+    "has no installation candidate",
+];
+const SIGNATURE_MESSAGES: [&str; 4] = [
+    "NO_PUBKEY",
+    "is not signed",
+    "signatures couldn't be verified",
+    "signatures were invalid",
+];
+const NETWORK_MESSAGES: [&str; 5] = [
+    "Temporary failure resolving",
+    "Could not resolve",
+    "Could not connect",
+    "Failed to fetch",
+    "Connection timed out",
+];
+const DEPENDENCY_MESSAGES: [&str; 3] = [
+    "Unmet dependencies",
+    "held broken packages",
+    "dependency problems",
+];
+
+fn contains_any(msg: &str, patterns: &[&str]) -> bool {
+    patterns.iter().any(|pattern| msg.contains(pattern))
+}
+
+/// Converts the error output of a failed apt or dpkg command into a typed PeachConfigError
+fn classify_failure(command: String, msg: String) -> PeachConfigError {
+    if contains_any(&msg, &NOT_FOUND_MESSAGES) {
+        PeachConfigError::AptNotFoundError { command, msg }
+    } else if contains_any(&msg, &SIGNATURE_MESSAGES) {
+        PeachConfigError::AptSignatureError { command, msg }
+    } else if contains_any(&msg, &NETWORK_MESSAGES) {
+        PeachConfigError::AptNetworkError { command, msg }
+    } else if contains_any(&msg, &DEPENDENCY_MESSAGES) {
+        PeachConfigError::AptDependencyError { command, msg }
+    } else if msg.contains(INTERRUPTED_MESSAGE) {
+        PeachConfigError::DpkgInterruptedError { command, msg }
+    } else {
+        PeachConfigError::CmdError { msg, command }
+    }
+}
+
+/// Runs an apt-get or dpkg command like cmd, but handles the dpkg lock and interrupted dpkg runs.
+///
+/// If another process such as unattended-upgrades holds the dpkg lock, the command is retried
+/// until the lock is released or the configured lock timeout passes, with a progress message
+/// every 30 seconds. If dpkg was interrupted, `dpkg --configure -a` is run once before retrying.
+/// Other failures are returned as typed errors, e.g. AptNetworkError or AptNotFoundError.
+///
+/// # Arguments
+///
+/// * `args` - the command and its arguments, e.g. ["apt-get", "install", "-y", "nginx"]
+/// * `dir` - the working directory of the command, or None for the current directory
+fn apt_cmd(args: &[&str], dir: Option<&str>) -> Result<Output, PeachConfigError> {
+    let timeout = Duration::from_secs(
+        load_settings()?
+            .apt_lock_timeout
+            .unwrap_or(DEFAULT_LOCK_TIMEOUT),
+    );
+    run_apt_cmd(args, timeout, LOCK_POLL_INTERVAL, true, &mut |args| {
+        cmd_with_env(args, &APT_ENV, dir)
+    })
+}
+
+/// Calls apt_cmd (above) and converts the standard output of the command to a String
+fn apt_output(args: &[&str]) -> Result<String, PeachConfigError> {
+    let output = apt_cmd(args, None)?;
+    let stdout = std::str::from_utf8(&output.stdout).context(CmdParseOutputError {
+        command: format!("{:?}", args),
+    })?;
+    Ok(stdout.strip_suffix('\n').unwrap_or(stdout).to_string())
+}
+
+/// Runs a command with the given runner, waiting for the dpkg lock and recovering from
+/// an interrupted dpkg run as described for apt_cmd. The runner is a parameter so that
+/// the retries can be tested without running apt.
+fn run_apt_cmd(
+    args: &[&str],
+    timeout: Duration,
+    poll_interval: Duration,
+    recover: bool,
+    run: &mut dyn FnMut(&[&str]) -> Result<Output, PeachConfigError>,
+) -> Result<Output, PeachConfigError> {
+    let start = Instant::now();
+    let mut last_progress: Option<Instant> = None;
+    let mut recovered = !recover;
+    loop {
+        let (command, msg) = match run(args) {
+            Ok(output) => return Ok(output),
+            Err(PeachConfigError::CmdError { command, msg }) => (command, msg),
+            Err(err) => return Err(err),
+        };
+        if contains_any(&msg, &LOCK_MESSAGES) {
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(PeachConfigError::AptLockTimeout {
+                    command,
+                    waited: waited.as_secs(),
+                });
+            }
+            if last_progress.is_none_or(|last| last.elapsed() >= LOCK_PROGRESS_INTERVAL) {
+                info!(
+                    "waiting for another package manager to release the dpkg lock ({}s of {}s)",
+                    waited.as_secs(),
+                    timeout.as_secs()
+                );
+                last_progress = Some(Instant::now());
+            }
+            thread::sleep(poll_interval);
+        } else if msg.contains(INTERRUPTED_MESSAGE) && !recovered {
+            warn!("dpkg was interrupted, running dpkg --configure -a before retrying");
+            recovered = true;
+            run_apt_cmd(
+                &["dpkg", "--configure", "-a"],
+                timeout,
+                poll_interval,
+                false,
+                run,
+            )?;
+        } else {
+            return Err(classify_failure(command, msg));
+        }
+    }
+}
+
+/// A package for apt to install, at the given version or otherwise at its candidate version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageSpec {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl PackageSpec {
+    /// Returns a spec for the candidate version of the given package
+    pub fn new(name: &str) -> PackageSpec {
+        PackageSpec {
+            name: name.to_string(),
+            version: None,
+        }
+    }
+
+    /// Returns a spec for the given version of the given package
+    pub fn at(name: &str, version: &str) -> PackageSpec {
+        PackageSpec {
+            name: name.to_string(),
+            version: Some(version.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for PackageSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}={}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A package which apt would upgrade, as listed by `apt list --upgradable`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpgradablePackage {
+    pub name: String,
+    // the version apt would install
+    pub candidate: String,
+    pub installed: Option<String>,
+    // the line of `apt list --upgradable` the package was parsed from
+    pub line: String,
+}
+
+/// Downloads the package lists of every configured apt repository
+pub fn refresh() -> Result<(), PeachConfigError> {
+    apt_cmd(&["apt-get", "update"], None)?;
+    Ok(())
+}
+
+/// Installs the given packages, upgrading them if they are already installed
+///
+/// # Arguments
+///
+/// * `packages` - the packages to install, each at its given version or at its candidate version
+/// * `allow_downgrades` - a bool, if true apt may install an older version than the one installed,
+///   which is needed to install a pinned or recorded version
+pub fn install(packages: &[PackageSpec], allow_downgrades: bool) -> Result<(), PeachConfigError> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+    let specs: Vec<String> = packages.iter().map(|spec| spec.to_string()).collect();
+    let mut install_cmd = vec!["apt-get", "install", "-y"];
+    install_cmd.extend(DPKG_CONF_OPTIONS.iter());
+    if allow_downgrades {
+        install_cmd.push("--allow-downgrades");
+    }
+    install_cmd.extend(specs.iter().map(|spec| spec.as_str()));
+    apt_cmd(&install_cmd, None)?;
+    Ok(())
+}
+
+/// Installs the given .deb files with dpkg, without resolving their dependencies from a repository
+pub fn install_files(files: &[&str]) -> Result<(), PeachConfigError> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let mut install_cmd = vec!["dpkg", "--force-confdef", "--force-confold", "--install"];
+    install_cmd.extend(files.iter());
+    apt_cmd(&install_cmd, None)?;
+    Ok(())
+}
+
+/// Downloads the .deb files of the given packages from the configured repositories into the
+/// given directory, without installing them or their dependencies
+///
+/// # Arguments
+///
+/// * `packages` - the packages to download, each at its given version or at its candidate version
+/// * `dir` - the directory the .deb files are saved to
+pub fn download(packages: &[PackageSpec], dir: &str) -> Result<(), PeachConfigError> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+    let specs: Vec<String> = packages.iter().map(|spec| spec.to_string()).collect();
+    let mut download_cmd = vec!["apt-get", "download"];
+    download_cmd.extend(specs.iter().map(|spec| spec.as_str()));
+    // apt-get download saves the .deb files to the current directory
+    apt_cmd(&download_cmd, Some(dir))?;
+    Ok(())
+}
+
+/// Returns the Debian changelog of the given package version, which apt downloads from the
+/// repository the version is available from
+pub fn changelog(package: &PackageSpec) -> Result<String, PeachConfigError> {
+    apt_output(&["apt-get", "changelog", &package.to_string()])
+}
+
+/// Removes the given packages along with any dependencies which are no longer needed.
+/// Their configuration files are kept.
+pub fn remove(packages: &[&str]) -> Result<(), PeachConfigError> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+    let mut remove_cmd = vec!["apt-get", "autoremove", "-y"];
+    remove_cmd.extend(packages.iter());
+    apt_cmd(&remove_cmd, None)?;
+    Ok(())
+}
+
+/// Removes the given packages along with their configuration files,
+/// and any dependencies which are no longer needed
+pub fn purge(packages: &[&str]) -> Result<(), PeachConfigError> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+    let mut purge_cmd = vec!["apt-get", "purge", "-y", "--autoremove"];
+    purge_cmd.extend(packages.iter());
+    apt_cmd(&purge_cmd, None)?;
+    Ok(())
+}
+
+/// Holds the given packages, so that apt neither installs, upgrades nor removes them,
+/// or releases the hold
+///
+/// # Arguments
+///
+/// * `packages` - the names of the packages
+/// * `held` - a bool, if true the packages are held and otherwise their hold is released
+pub fn set_hold(packages: &[&str], held: bool) -> Result<(), PeachConfigError> {
+    let mut mark_cmd = vec!["apt-mark", if held { "hold" } else { "unhold" }];
+    mark_cmd.extend(packages.iter());
+    apt_cmd(&mark_cmd, None)?;
+    Ok(())
+}
+
+/// Parses the output of `apt list --upgradable`, whose lines have the form
+/// "peach-web/buster 0.6.1 arm64 [upgradable from: 0.6.0]"
+fn parse_upgradable(list: &str) -> Vec<UpgradablePackage> {
+    list.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let (name, _) = words.next()?.split_once('/')?;
+            let candidate = words.next()?;
+            let installed = line
+                .split_once("[upgradable from: ")
+                .map(|(_, installed)| installed.trim_end_matches(']').to_string());
+            Some(UpgradablePackage {
+                name: name.to_string(),
+                candidate: candidate.to_string(),
+                installed,
+                line: line.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Returns every installed package which has a newer candidate version,
+/// according to the package lists last downloaded by refresh
+pub fn list_upgradable() -> Result<Vec<UpgradablePackage>, PeachConfigError> {
+    let list = apt_output(&["apt", "list", "--upgradable"])?;
+    Ok(parse_upgradable(&list))
+}
+
+/// Returns the installed version of each of the given packages which dpkg reports as fully
+/// installed, keyed by package name. Removed packages whose configuration files remain are left out.
+pub fn installed_versions(names: &[&str]) -> Result<HashMap<String, String>, PeachConfigError> {
+    let versions = read_package_status(names)?
+        .into_iter()
+        .filter(|(_, status)| status.is_installed())
+        .filter_map(|(name, status)| status.version.map(|version| (name, version)))
+        .collect();
+    Ok(versions)
+}
+
+/// Parses the output of `apt-cache policy <packages>` into a map of package name to
+/// candidate version, leaving out packages without a candidate
+fn parse_candidates(policy: &str) -> HashMap<String, String> {
+    let mut candidates = HashMap::new();
+    let mut package: Option<&str> = None;
+    for line in policy.lines() {
+        if !line.starts_with(' ') && line.ends_with(':') {
+            package = Some(line.trim_end_matches(':'));
+        } else if let Some(candidate) = line.trim().strip_prefix("Candidate:") {
+            let candidate = candidate.trim();
+            if let (Some(name), false) = (package, candidate == "(none)") {
+                candidates.insert(name.to_string(), candidate.to_string());
+            }
+        }
+    }
+    candidates
+}
+
+/// Returns the version apt would install of each of the given packages, taking pins into
+/// account, keyed by package name. Packages which no repository provides are left out.
+pub fn candidate_versions(names: &[&str]) -> Result<HashMap<String, String>, PeachConfigError> {
+    let mut policy_cmd = vec!["apt-cache", "policy"];
+    policy_cmd.extend(names.iter());
+    Ok(parse_candidates(&apt_output(&policy_cmd)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn success() -> Result<Output, PeachConfigError> {
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        })
+    }
+
+    fn failure(msg: &str) -> Result<Output, PeachConfigError> {
+        Err(PeachConfigError::CmdError {
+            command: "apt-get install -y peach-web".to_string(),
+            msg: msg.to_string(),
+        })
+    }
+
+    /// Runs run_apt_cmd with a runner which returns the given results in order,
+    /// returning its result and the commands it ran
+    fn run_scripted(
+        timeout: Duration,
+        results: Vec<Result<Output, PeachConfigError>>,
+    ) -> (Result<Output, PeachConfigError>, Vec<String>) {
+        let mut results = results.into_iter();
+        let mut commands = Vec::new();
+        let result = run_apt_cmd(
+            &["apt-get", "install", "-y", "peach-web"],
+            timeout,
+            Duration::ZERO,
+            true,
+            &mut |args| {
+                commands.push(args.join(" "));
+                results.next().expect("runner called too often")
+            },
+        );
+        (result, commands)
+    }
+
+    #[test]
+    fn package_spec_formatting() {
+        assert_eq!(PackageSpec::new("peach-web").to_string(), "peach-web");
+        assert_eq!(
+            PackageSpec::at("peach-web", "1:0.6.1-1").to_string(),
+            "peach-web=1:0.6.1-1"
+        );
+    }
+
+    #[test]
+    fn upgradable_packages_are_parsed() {
+        let list = "Listing... Done\n\
+                    peach-web/buster 0.6.1 arm64 [upgradable from: 0.6.0]\n\
+                    peach-oled/buster,now 1:0.2.0-1 arm64 [upgradable from: 0.1.9]\n\
+                    peach-menu/buster 0.3.0 arm64";
+        let packages = parse_upgradable(list);
+        assert_eq!(packages.len(), 3);
+        assert_eq!(packages[0].name, "peach-web");
+        assert_eq!(packages[0].candidate, "0.6.1");
+        assert_eq!(packages[0].installed.as_deref(), Some("0.6.0"));
+        assert_eq!(
+            packages[0].line,
+            "peach-web/buster 0.6.1 arm64 [upgradable from: 0.6.0]"
+        );
+        assert_eq!(packages[1].candidate, "1:0.2.0-1");
+        assert_eq!(packages[1].installed.as_deref(), Some("0.1.9"));
+        assert_eq!(packages[2].installed, None);
+    }
+
+    #[test]
+    fn candidates_without_a_version_are_left_out() {
+        let policy = "peach-web:\n  \
+                      Installed: 0.6.0\n  \
+                      Candidate: 0.6.1\n  \
+                      Version table:\n \
+                      *** 0.6.1 500\n        \
+                      500 http://apt.peachcloud.org buster/main arm64 Packages\n\
+                      peach-missing:\n  \
+                      Installed: (none)\n  \
+                      Candidate: (none)\n\
+                      peach-oled:\n  \
+                      Installed: (none)\n  \
+                      Candidate: 1:0.2.0-1\n";
+        let candidates = parse_candidates(policy);
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates["peach-web"], "0.6.1");
+        assert_eq!(candidates["peach-oled"], "1:0.2.0-1");
+    }
+
+    #[test]
+    fn failures_are_classified() {
+        let classify = |msg: &str| classify_failure("apt-get".to_string(), msg.to_string());
+        assert!(matches!(
+            classify("E: Unable to locate package peach-wbe"),
+            PeachConfigError::AptNotFoundError { .. }
+        ));
+        assert!(matches!(
+            classify("W: GPG error: NO_PUBKEY 0123456789ABCDEF"),
+            PeachConfigError::AptSignatureError { .. }
+        ));
+        assert!(matches!(
+            classify("W: Failed to fetch http://apt.peachcloud.org/dists/buster/InRelease"),
+            PeachConfigError::AptNetworkError { .. }
+        ));
+        assert!(matches!(
+            classify("E: Unmet dependencies. Try 'apt --fix-broken install'"),
+            PeachConfigError::AptDependencyError { .. }
+        ));
+        assert!(matches!(
+            classify("E: dpkg was interrupted, you must manually run 'dpkg --configure -a'"),
+            PeachConfigError::DpkgInterruptedError { .. }
+        ));
+        assert!(matches!(
+            classify("E: Sub-process /usr/bin/dpkg returned an error code (1)"),
+            PeachConfigError::CmdError { .. }
+        ));
+    }
+
+    #[test]
+    fn command_is_retried_until_the_dpkg_lock_is_released() {
+        let (result, commands) = run_scripted(
+            Duration::from_secs(60),
+            vec![
+                failure("E: Could not get lock /var/lib/dpkg/lock-frontend"),
+                failure("E: Unable to acquire the dpkg frontend lock"),
+                success(),
+            ],
+        );
+        assert!(result.is_ok());
+        assert_eq!(commands.len(), 3);
+    }
+
+    #[test]
+    fn waiting_for_the_dpkg_lock_times_out() {
+        let (result, commands) = run_scripted(
+            Duration::ZERO,
+            vec![failure("E: Could not get lock /var/lib/dpkg/lock-frontend")],
+        );
+        assert!(matches!(
+            result,
+            Err(PeachConfigError::AptLockTimeout { .. })
+        ));
+        assert_eq!(commands.len(), 1);
+    }
+
+    #[test]
+    fn interrupted_dpkg_is_configured_once_before_retrying() {
+        let interrupted = "E: dpkg was interrupted, you must manually run 'dpkg --configure -a'";
+        let (result, commands) = run_scripted(
+            Duration::from_secs(60),
+            vec![failure(interrupted), success(), success()],
+        );
+        assert!(result.is_ok());
+        assert_eq!(
+            commands,
+            [
+                "apt-get install -y peach-web",
+                "dpkg --configure -a",
+                "apt-get install -y peach-web"
+            ]
+        );
+
+        let (result, commands) = run_scripted(
+            Duration::from_secs(60),
+            vec![failure(interrupted), success(), failure(interrupted)],
+        );
+        assert!(matches!(
+            result,
+            Err(PeachConfigError::DpkgInterruptedError { .. })
+        ));
+        assert_eq!(commands.len(), 3);
+    }
+
+    #[test]
+    fn other_failures_are_not_retried() {
+        let (result, commands) = run_scripted(
+            Duration::from_secs(60),
+            vec![failure("E: Unable to locate package peach-web")],
+        );
+        assert!(matches!(
+            result,
+            Err(PeachConfigError::AptNotFoundError { .. })
+        ));
+        assert_eq!(commands.len(), 1);
+    }
+}
@@ -0,0 +1,353 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error::{FileWriteError, PeachConfigError};
+use crate::health::is_unit_active;
+use crate::history::{load_history, record_history, Trigger};
+use crate::settings::{load_settings, save_settings};
+use crate::setup_peach_deb::update_package_lists;
+use crate::update::{
+    get_available_updates, peach_config_binary, reexec_self, run_update_self, ResumePoint,
+    UpdateKind,
+};
+use crate::utils::{cmd, get_output};
+
+// Name of the systemd timer and service which run automatic updates
+pub const AUTO_UPDATE_UNIT: &str = "peach-config-auto-update";
+
+// Directory which the auto-update units are written to
+const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+
+// Maintenance window used if none is configured
+pub const DEFAULT_MAINTENANCE_WINDOW: &str = "03:00-05:00";
+
+/// A daily period of local time, given as HH:MM-HH:MM, in which automatic updates may be applied.
+/// A window whose end is before its start continues past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MaintenanceWindow {
+    // minutes after midnight
+    start: u32,
+    end: u32,
+}
+
+/// Parses a time of the form HH:MM into minutes after midnight
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let window = s
+            .split_once('-')
+            .and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)));
+        match window {
+            Some((start, end)) if start != end => Ok(MaintenanceWindow { start, end }),
+            _ => Err(format!(
+                "invalid maintenance window {}, expected HH:MM-HH:MM e.g. {}",
+                s, DEFAULT_MAINTENANCE_WINDOW
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for MaintenanceWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<MaintenanceWindow> for String {
+    fn from(window: MaintenanceWindow) -> Self {
+        window.to_string()
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl Default for MaintenanceWindow {
+    fn default() -> Self {
+        DEFAULT_MAINTENANCE_WINDOW
+            .parse()
+            .expect("default maintenance window is valid")
+    }
+}
+
+impl MaintenanceWindow {
+    /// Returns the length of the window in minutes
+    pub fn length(&self) -> u32 {
+        (self.end + 24 * 60 - self.start) % (24 * 60)
+    }
+
+    /// Returns true if the given number of minutes after midnight is inside the window
+    pub fn contains(&self, minutes: u32) -> bool {
+        (minutes + 24 * 60 - self.start) % (24 * 60) < self.length()
+    }
+}
+
+/// Returns the current local time in minutes after midnight
+fn local_minutes() -> Result<u32, PeachConfigError> {
+    let time = get_output(&["date", "+%H:%M"])?;
+    parse_time(&time).ok_or_else(|| PeachConfigError::CmdError {
+        msg: format!("unexpected time {}", time),
+        command: "date +%H:%M".to_string(),
+    })
+}
+
+/// Writes the systemd service which runs `peach-config auto-update run`, and the timer which
+/// starts it at a random time in the first half of the maintenance window every day
+fn write_auto_update_units(window: &MaintenanceWindow) -> Result<(), PeachConfigError> {
+    let service_file = format!("{}/{}.service", SYSTEMD_UNIT_DIR, AUTO_UPDATE_UNIT);
+    let service = format!(
+        "[Unit]\n\
+         Description=Apply PeachCloud updates in the maintenance window\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={} --wait auto-update run\n",
+        peach_config_binary()?.display()
+    );
+    fs::write(&service_file, service).context(FileWriteError { file: service_file })?;
+
+    let timer_file = format!("{}/{}.timer", SYSTEMD_UNIT_DIR, AUTO_UPDATE_UNIT);
+    let timer = format!(
+        "[Unit]\n\
+         Description=Apply PeachCloud updates daily at {}\n\
+         \n\
+         [Timer]\n\
+         OnCalendar=*-*-* {:02}:{:02}:00\n\
+         RandomizedDelaySec={}\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        window,
+        window.start / 60,
+        window.start % 60,
+        window.length() / 2 * 60
+    );
+    fs::write(&timer_file, timer).context(FileWriteError { file: timer_file })?;
+    Ok(())
+}
+
+/// Turns on automatic updates, which are applied during the given maintenance window,
+/// or the saved window if none is given
+pub fn enable_auto_update(window: Option<MaintenanceWindow>) -> Result<(), PeachConfigError> {
+    let mut settings = load_settings()?;
+    let window = window.or(settings.maintenance_window).unwrap_or_default();
+    write_auto_update_units(&window)?;
+    cmd(&["systemctl", "daemon-reload"])?;
+    cmd(&[
+        "systemctl",
+        "enable",
+        "--now",
+        &format!("{}.timer", AUTO_UPDATE_UNIT),
+    ])?;
+    settings.auto_update = true;
+    settings.maintenance_window = Some(window);
+    save_settings(&settings)?;
+    info!("automatic updates enabled in the window {}", window);
+    Ok(())
+}
+
+/// Turns off automatic updates and removes the auto-update units
+pub fn disable_auto_update() -> Result<(), PeachConfigError> {
+    let timer = format!("{}.timer", AUTO_UPDATE_UNIT);
+    let timer_file = format!("{}/{}", SYSTEMD_UNIT_DIR, timer);
+    if Path::new(&timer_file).exists() {
+        cmd(&["systemctl", "disable", "--now", &timer])?;
+    }
+    for extension in &["timer", "service"] {
+        let file = format!("{}/{}.{}", SYSTEMD_UNIT_DIR, AUTO_UPDATE_UNIT, extension);
+        if Path::new(&file).exists() {
+            fs::remove_file(&file).context(FileWriteError { file })?;
+        }
+    }
+    cmd(&["systemctl", "daemon-reload"])?;
+    let mut settings = load_settings()?;
+    settings.auto_update = false;
+    save_settings(&settings)?;
+    info!("automatic updates disabled");
+    Ok(())
+}
+
+/// Prints whether automatic updates are enabled, the maintenance window,
+/// when the timer next runs and the result of the last automatic update
+pub fn auto_update_status() -> Result<(), PeachConfigError> {
+    let settings = load_settings()?;
+    let timer = format!("{}.timer", AUTO_UPDATE_UNIT);
+    println!(
+        "auto-update: {}",
+        if settings.auto_update {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    println!(
+        "maintenance window: {}",
+        settings.maintenance_window.unwrap_or_default()
+    );
+    if is_unit_active(&timer) {
+        let next = get_output(&[
+            "systemctl",
+            "show",
+            "--property=NextElapseUSecRealtime",
+            "--value",
+            &timer,
+        ])?;
+        println!("next run: {}", next);
+    }
+    let last = load_history()?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.trigger == Trigger::AutoUpdate);
+    match last {
+        Some(entry) => println!(
+            "last update: {} {}{}",
+            entry.timestamp,
+            entry.result,
+            entry
+                .error
+                .map(|err| format!(" ({})", err))
+                .unwrap_or_default()
+        ),
+        None => println!("last update: never"),
+    }
+    Ok(())
+}
+
+/// Applies available upgrades if automatic updates are enabled and the local time is inside the
+/// maintenance window. Services are health checked afterwards and rolled back if a service which
+/// was healthy before the update fails its check, and the update is recorded in the update history.
+/// Services are never installed or downgraded to a pinned version without a person present,
+/// those changes are left to `peach-config update`.
+///
+/// This is run by the auto-update timer.
+pub fn run_auto_update() -> Result<(), PeachConfigError> {
+    let settings = load_settings()?;
+    if !settings.auto_update {
+        info!("automatic updates are disabled");
+        return Ok(());
+    }
+    let window = settings.maintenance_window.unwrap_or_default();
+    if !window.contains(local_minutes()?) {
+        info!("outside of the maintenance window {}", window);
+        return Ok(());
+    }
+    update_package_lists()?;
+    let updates: Vec<String> = get_available_updates()?
+        .into_iter()
+        .filter(|update| update.kind == UpdateKind::Upgrade)
+        .map(|update| update.name)
+        .collect();
+    if updates.is_empty() {
+        info!("all services are up to date");
+        return Ok(());
+    }
+    info!("applying updates to: {}", updates.join(", "));
+    record_history(Trigger::AutoUpdate, run_update_self)?;
+    // the newly installed peach-config updates the other microservices,
+    // recording its own entry in the update history
+    reexec_self(&[
+        "update".to_string(),
+        "--resume".to_string(),
+        ResumePoint::AutoUpdate.to_string(),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(s: &str) -> MaintenanceWindow {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn window_parse_and_display() {
+        let parsed = window("3:05-05:00");
+        assert_eq!(
+            parsed,
+            MaintenanceWindow {
+                start: 185,
+                end: 300
+            }
+        );
+        assert_eq!(parsed.to_string(), "03:05-05:00");
+        assert_eq!(parsed.length(), 115);
+        assert_eq!(
+            MaintenanceWindow::default().to_string(),
+            DEFAULT_MAINTENANCE_WINDOW
+        );
+    }
+
+    #[test]
+    fn invalid_windows_are_rejected() {
+        for invalid in &[
+            "",
+            "03:00",
+            "03:00-03:00",
+            "24:00-01:00",
+            "03:60-04:00",
+            "3-5",
+            "03:00-05:00-06:00",
+            "aa:bb-cc:dd",
+        ] {
+            assert!(
+                invalid.parse::<MaintenanceWindow>().is_err(),
+                "{} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn window_contains_its_start_but_not_its_end() {
+        let window = window("03:00-05:00");
+        assert!(!window.contains(2 * 60 + 59));
+        assert!(window.contains(3 * 60));
+        assert!(window.contains(4 * 60 + 59));
+        assert!(!window.contains(5 * 60));
+    }
+
+    #[test]
+    fn window_wraps_past_midnight() {
+        let window = window("23:30-01:00");
+        assert_eq!(window.length(), 90);
+        assert!(!window.contains(23 * 60 + 29));
+        assert!(window.contains(23 * 60 + 30));
+        assert!(window.contains(0));
+        assert!(window.contains(59));
+        assert!(!window.contains(60));
+        assert!(!window.contains(12 * 60));
+    }
+}
@@ -0,0 +1,164 @@
+use clap::arg_enum;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::error::PeachConfigError;
+
+// File paths which are read to detect which board peach-config is running on
+pub const DEVICE_TREE_MODEL_FILE: &str = "/proc/device-tree/model";
+pub const CPUINFO_FILE: &str = "/proc/cpuinfo";
+
+arg_enum! {
+    /// enum options for the hardware boards which PeachCloud can be installed on
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[derive(Serialize, Deserialize)]
+    pub enum Board {
+        Pi3,
+        Pi4,
+        PiZero2,
+        GenericArm64,
+        GenericAmd64
+    }
+}
+
+/// Describes which hardware specific files and settings should be installed for a board.
+#[derive(Debug)]
+pub struct BoardProfile {
+    pub board: Board,
+    // directory containing the raspberry pi firmware and config.txt,
+    // None for boards which do not boot via the raspberry pi firmware
+    pub firmware_dir: Option<&'static str>,
+    // device tree blobs in the conf dir which are copied into the firmware dir
+    pub firmware_assets: &'static [&'static str],
+    // device tree overlay in the conf dir which enables pull-up resistors for the buttons
+    pub gpio_overlay: Option<&'static str>,
+    // number of the i2c bus which the OLED and RTC are attached to
+    pub i2c_bus: Option<u8>,
+    // kernel modules which need to be loaded for i2c to work
+    pub i2c_modules: &'static [&'static str],
+    // udev rules in the conf dir which give gpio-user access to the gpio chips
+    pub gpio_rules: Option<&'static str>,
+}
+
+impl BoardProfile {
+    /// Returns the path to config.txt for boards which use the raspberry pi firmware
+    pub fn config_txt(&self) -> Option<String> {
+        self.firmware_dir.map(|dir| format!("{}/config.txt", dir))
+    }
+}
+
+/// Returns the hardware profile for the given board
+pub fn board_profile(board: Board) -> BoardProfile {
+    match board {
+        Board::Pi3 => BoardProfile {
+            board,
+            firmware_dir: Some("/boot/firmware"),
+            firmware_assets: &["bcm2710-rpi-3-b.dtb"],
+            gpio_overlay: Some("mygpio.dtbo"),
+            i2c_bus: Some(1),
+            i2c_modules: &["i2c-dev", "i2c-bcm2835"],
+            gpio_rules: Some("50-gpio.rules"),
+        },
+        // the device tree blobs for these boards are provided by the raspi-firmware package
+        Board::Pi4 | Board::PiZero2 => BoardProfile {
+            board,
+            firmware_dir: Some("/boot/firmware"),
+            firmware_assets: &[],
+            gpio_overlay: Some("mygpio.dtbo"),
+            i2c_bus: Some(1),
+            i2c_modules: &["i2c-dev", "i2c-bcm2835"],
+            gpio_rules: Some("50-gpio.rules"),
+        },
+        Board::GenericArm64 => BoardProfile {
+            board,
+            firmware_dir: None,
+            firmware_assets: &[],
+            gpio_overlay: None,
+            i2c_bus: None,
+            i2c_modules: &["i2c-dev"],
+            gpio_rules: Some("50-gpio.rules"),
+        },
+        Board::GenericAmd64 => BoardProfile {
+            board,
+            firmware_dir: None,
+            firmware_assets: &[],
+            gpio_overlay: None,
+            i2c_bus: None,
+            i2c_modules: &["i2c-dev"],
+            gpio_rules: None,
+        },
+    }
+}
+
+/// Maps a model string, as found in the device tree or /proc/cpuinfo, to a Board
+fn board_from_model(model: &str) -> Option<Board> {
+    if model.starts_with("Raspberry Pi 3") {
+        Some(Board::Pi3)
+    } else if model.starts_with("Raspberry Pi 4") {
+        Some(Board::Pi4)
+    } else if model.starts_with("Raspberry Pi Zero 2") {
+        Some(Board::PiZero2)
+    } else {
+        None
+    }
+}
+
+/// Reads the model name of the board from the device tree,
+/// falling back to the Model line of /proc/cpuinfo.
+///
+/// Returns None if neither file contains a model name.
+pub fn read_board_model() -> Option<String> {
+    if let Ok(contents) = fs::read_to_string(DEVICE_TREE_MODEL_FILE) {
+        // the device tree model string is null terminated
+        let model = contents.trim_end_matches('\0').trim().to_string();
+        if !model.is_empty() {
+            return Some(model);
+        }
+    }
+    let cpuinfo = fs::read_to_string(CPUINFO_FILE).ok()?;
+    cpuinfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "Model")
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Detects which board peach-config is running on.
+///
+/// Raspberry Pi models are detected from their model name. Any other board is treated
+/// as a generic Debian installation of the cpu architecture peach-config was built for.
+/// There is no profile for 32-bit arm boards other than the Raspberry Pi models, so on those
+/// a BoardDetectionError is returned and the board must be selected with --board.
+pub fn detect_board() -> Result<Board, PeachConfigError> {
+    let model = read_board_model();
+    if let Some(board) = model.as_deref().and_then(board_from_model) {
+        info!("detected board: {}", board);
+        return Ok(board);
+    }
+    let board = match std::env::consts::ARCH {
+        "aarch64" => Board::GenericArm64,
+        "x86_64" => Board::GenericAmd64,
+        "arm" => {
+            return Err(PeachConfigError::BoardDetectionError {
+                msg: format!(
+                    "no hardware profile for 32-bit arm board {}, select a board with --board",
+                    model.as_deref().unwrap_or("of unknown model")
+                ),
+            })
+        }
+        arch => {
+            return Err(PeachConfigError::BoardDetectionError {
+                msg: format!("unsupported cpu architecture: {}", arch),
+            })
+        }
+    };
+    if let Some(model) = model {
+        warn!(
+            "no hardware profile for board \"{}\", using {} profile",
+            model, board
+        );
+    }
+    info!("detected board: {}", board);
+    Ok(board)
+}
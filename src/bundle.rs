@@ -0,0 +1,378 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::apt::{candidate_versions, download, install_files, installed_versions, PackageSpec};
+use crate::dpkg::parse_control_file;
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+use crate::generate_manifest::get_currently_installed_microservices;
+use crate::setup_peach_deb::KEYRING_FILE;
+use crate::utils::{cmd, get_output, now_timestamp, TempDir};
+
+// Version of the bundle format, checked before a bundle is installed
+pub const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+// File in the root of a bundle which lists the packages it contains
+pub const BUNDLE_MANIFEST_FILE: &str = "bundle.json";
+
+// Detached gpg signature of BUNDLE_MANIFEST_FILE
+pub const BUNDLE_SIGNATURE_FILE: &str = "bundle.json.sig";
+
+// Directory in which apt caches the packages it downloads
+const APT_ARCHIVES_DIR: &str = "/var/cache/apt/archives";
+
+/// A .deb file contained in a bundle
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundlePackage {
+    pub name: String,
+    pub version: String,
+    pub architecture: String,
+    // name of the .deb file, relative to the root of the bundle
+    pub file: String,
+    // sha256 checksum of the .deb file as a hex string
+    pub sha256: String,
+    // names of the packages the package depends on, including alternatives
+    pub depends: Vec<String>,
+}
+
+/// The manifest of an offline bundle, which is signed so that the checksums of
+/// the .deb files it lists can be trusted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub format_version: u32,
+    // time at which the bundle was created, as an RFC 3339 timestamp in UTC
+    pub created_at: String,
+    pub packages: Vec<BundlePackage>,
+}
+
+fn bundle_error(msg: String) -> PeachConfigError {
+    PeachConfigError::BundleError { msg }
+}
+
+/// Returns true if the path is a tarball rather than a directory
+fn is_tarball(path: &str) -> bool {
+    [".tar", ".tar.gz", ".tgz", ".tar.xz"]
+        .iter()
+        .any(|extension| path.ends_with(extension))
+}
+
+/// Returns the sha256 checksum of a file as a hex string
+fn sha256(path: &str) -> Result<String, PeachConfigError> {
+    let output = get_output(&["sha256sum", path])?;
+    output
+        .split_whitespace()
+        .next()
+        .map(|checksum| checksum.to_string())
+        .ok_or_else(|| bundle_error(format!("could not read checksum of {}", path)))
+}
+
+/// Parses a Depends field into the names of the packages it mentions, e.g.
+/// "libc6 (>= 2.28), peach-lib | peach-lib-dev" into ["libc6", "peach-lib", "peach-lib-dev"]
+fn parse_depends(depends: &str) -> Vec<String> {
+    depends
+        .split([',', '|'])
+        .filter_map(|dependency| {
+            dependency
+                .split(|c: char| c.is_whitespace() || c == '(' || c == ':')
+                .find(|name| !name.is_empty())
+                .map(|name| name.to_string())
+        })
+        .collect()
+}
+
+/// Reads the name, version, architecture and dependencies of a .deb file
+/// and records it as a package of the bundle
+fn bundle_package(dir: &str, file: &str) -> Result<BundlePackage, PeachConfigError> {
+    let path = format!("{}/{}", dir, file);
+    let control = get_output(&["dpkg-deb", "--field", &path])?;
+    let mut fields = parse_control_file(&control)
+        .into_iter()
+        .next()
+        .unwrap_or_default();
+    let mut field = |name: &str| {
+        fields
+            .remove(name)
+            .ok_or_else(|| bundle_error(format!("{} has no {} field", file, name)))
+    };
+    Ok(BundlePackage {
+        name: field("Package")?,
+        version: field("Version")?,
+        architecture: field("Architecture")?,
+        depends: parse_depends(&field("Depends").unwrap_or_default()),
+        file: file.to_string(),
+        sha256: sha256(&path)?,
+    })
+}
+
+/// Copies the .deb of the given package version into the bundle directory, from the apt cache
+/// if it is there and otherwise by downloading it from the configured repositories
+fn fetch_deb(dir: &str, name: &str, version: &str) -> Result<(), PeachConfigError> {
+    // apt escapes the epoch separator of the version in the file names of its cache
+    let prefix = format!("{}_{}_", name, version.replace(':', "%3a"));
+    let cached = fs::read_dir(APT_ARCHIVES_DIR).ok().and_then(|entries| {
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .find(|file| file.starts_with(&prefix) && file.ends_with(".deb"))
+    });
+    if let Some(file) = cached {
+        info!("copying {} from the apt cache", file);
+        cmd(&[
+            "cp",
+            &format!("{}/{}", APT_ARCHIVES_DIR, file),
+            &format!("{}/{}", dir, file),
+        ])?;
+        return Ok(());
+    }
+    info!("downloading {}={}", name, version);
+    download(&[PackageSpec::at(name, version)], dir)
+}
+
+/// Creates an offline bundle of .deb files, which can be installed on a device without
+/// network access with `peach-config update --from`
+///
+/// # Arguments
+///
+/// * `output` - path of the bundle to create, either a directory or a tarball
+///   ending in .tar, .tar.gz, .tgz or .tar.xz
+/// * `packages` - packages to include, each of the form name[=version], if empty the
+///   PeachCloud services installed on the running device are included at their installed versions
+/// * `sign_with` - optional gpg key id which the bundle manifest is signed with,
+///   if not supplied the default key of gpg is used. Unless this is the PeachCloud repository
+///   key, devices installing the bundle must be given the public key with `--keyring`
+pub fn create_bundle(
+    output: &str,
+    packages: &[String],
+    sign_with: Option<&str>,
+) -> Result<(), PeachConfigError> {
+    let specs: Vec<(String, Option<String>)> = if packages.is_empty() {
+        let mut installed: Vec<(String, Option<String>)> = get_currently_installed_microservices()?
+            .into_iter()
+            .map(|(name, version)| (name, Some(version)))
+            .collect();
+        installed.sort();
+        installed
+    } else {
+        packages
+            .iter()
+            .map(|spec| match spec.split_once('=') {
+                Some((name, version)) => (name.to_string(), Some(version.to_string())),
+                None => (spec.to_string(), None),
+            })
+            .collect()
+    };
+    if specs.is_empty() {
+        return Err(bundle_error("no packages to bundle".to_string()));
+    }
+
+    // a tarball is built in a private temporary directory, which is removed once it is packed
+    let work_dir = if is_tarball(output) {
+        Some(TempDir::new()?)
+    } else {
+        None
+    };
+    let dir = if let Some(work_dir) = &work_dir {
+        work_dir.path.clone()
+    } else {
+        let not_empty = fs::read_dir(output).is_ok_and(|mut entries| entries.next().is_some());
+        if not_empty {
+            return Err(bundle_error(format!("{} is not empty", output)));
+        }
+        output.to_string()
+    };
+    fs::create_dir_all(&dir).context(FileWriteError { file: dir.clone() })?;
+
+    // fetch each package at the requested version, or the candidate version apt would install
+    let unversioned: Vec<&str> = specs
+        .iter()
+        .filter(|(_, version)| version.is_none())
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let candidates = if unversioned.is_empty() {
+        HashMap::new()
+    } else {
+        candidate_versions(&unversioned)?
+    };
+    for (name, version) in &specs {
+        let version = match version {
+            Some(version) => version,
+            None => candidates
+                .get(name)
+                .ok_or_else(|| bundle_error(format!("no version of {} is available", name)))?,
+        };
+        fetch_deb(&dir, name, version)?;
+    }
+
+    let mut files: Vec<String> = fs::read_dir(&dir)
+        .context(FileReadError { file: dir.clone() })?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|file| file.ends_with(".deb"))
+        .collect();
+    files.sort();
+    let manifest = BundleManifest {
+        format_version: BUNDLE_FORMAT_VERSION,
+        created_at: now_timestamp(),
+        packages: files
+            .iter()
+            .map(|file| bundle_package(&dir, file))
+            .collect::<Result<_, _>>()?,
+    };
+    let manifest_path = format!("{}/{}", dir, BUNDLE_MANIFEST_FILE);
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?).context(
+        FileWriteError {
+            file: manifest_path.clone(),
+        },
+    )?;
+
+    let signature_path = format!("{}/{}", dir, BUNDLE_SIGNATURE_FILE);
+    let mut sign_cmd = vec!["gpg", "--batch", "--yes", "--detach-sign"];
+    if let Some(key) = sign_with {
+        sign_cmd.extend(["--local-user", key]);
+    }
+    sign_cmd.extend(["--output", &signature_path, &manifest_path]);
+    cmd(&sign_cmd)?;
+
+    if is_tarball(output) {
+        cmd(&["tar", "-caf", output, "-C", &dir, "."])?;
+    }
+    info!(
+        "created bundle {} with {} packages",
+        output,
+        manifest.packages.len()
+    );
+    Ok(())
+}
+
+/// Verifies the signature of the bundle manifest against the given keyring, then the checksum
+/// and architecture of every .deb it lists, and returns the manifest
+fn verify_bundle(dir: &str, keyring: &str) -> Result<BundleManifest, PeachConfigError> {
+    let manifest_path = format!("{}/{}", dir, BUNDLE_MANIFEST_FILE);
+    let signature_path = format!("{}/{}", dir, BUNDLE_SIGNATURE_FILE);
+    if !Path::new(&signature_path).exists() {
+        return Err(bundle_error(format!(
+            "{} is missing",
+            BUNDLE_SIGNATURE_FILE
+        )));
+    }
+    cmd(&[
+        "gpgv",
+        "--keyring",
+        keyring,
+        &signature_path,
+        &manifest_path,
+    ])?;
+    let contents = fs::read_to_string(&manifest_path).context(FileReadError {
+        file: manifest_path.clone(),
+    })?;
+    let manifest: BundleManifest = serde_json::from_str(&contents)?;
+    if manifest.format_version != BUNDLE_FORMAT_VERSION {
+        return Err(bundle_error(format!(
+            "bundle format version {} is not supported, expected {}",
+            manifest.format_version, BUNDLE_FORMAT_VERSION
+        )));
+    }
+
+    let architecture = get_output(&["dpkg", "--print-architecture"])?;
+    for package in &manifest.packages {
+        if package.file.contains('/') {
+            return Err(bundle_error(format!("invalid file name {}", package.file)));
+        }
+        if package.architecture != "all" && package.architecture != architecture {
+            return Err(bundle_error(format!(
+                "{} is built for {}, this device is {}",
+                package.file, package.architecture, architecture
+            )));
+        }
+        let checksum = sha256(&format!("{}/{}", dir, package.file))?;
+        if checksum != package.sha256 {
+            return Err(bundle_error(format!(
+                "checksum of {} does not match the bundle manifest",
+                package.file
+            )));
+        }
+    }
+    Ok(manifest)
+}
+
+/// Orders the packages so that each comes after the packages of the bundle it depends on.
+/// Packages which depend on each other are returned together in one group, which dpkg installs
+/// in a single call, and every other group contains a single package.
+fn install_order(packages: &[BundlePackage]) -> Vec<Vec<&BundlePackage>> {
+    let names: BTreeSet<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    let mut remaining: BTreeMap<&str, &BundlePackage> =
+        packages.iter().map(|p| (p.name.as_str(), p)).collect();
+    let mut groups = Vec::new();
+    while !remaining.is_empty() {
+        let ready: Vec<&str> = remaining
+            .values()
+            .filter(|package| {
+                package.depends.iter().all(|dependency| {
+                    !names.contains(dependency.as_str())
+                        || dependency == &package.name
+                        || !remaining.contains_key(dependency.as_str())
+                })
+            })
+            .map(|package| package.name.as_str())
+            .collect();
+        if ready.is_empty() {
+            // the remaining packages depend on each other
+            groups.push(remaining.values().copied().collect());
+            break;
+        }
+        for name in ready {
+            if let Some(package) = remaining.remove(name) {
+                groups.push(vec![package]);
+            }
+        }
+    }
+    groups
+}
+
+/// Installs the packages of an offline bundle with dpkg, in dependency order
+///
+/// # Arguments
+///
+/// * `from` - path to the bundle, either a directory or a tarball
+/// * `keyring` - optional path to the keyring which the bundle manifest must be signed by,
+///   if not supplied the keyring of the PeachCloud apt repository is used
+///
+/// Packages which are already installed at the version in the bundle are skipped.
+pub fn install_bundle(from: &str, keyring: Option<&str>) -> Result<(), PeachConfigError> {
+    // a tarball is extracted to a private temporary directory, which is removed afterwards
+    let work_dir = if is_tarball(from) {
+        Some(TempDir::new()?)
+    } else {
+        None
+    };
+    let dir = if let Some(work_dir) = &work_dir {
+        cmd(&["tar", "-xf", from, "-C", &work_dir.path])?;
+        work_dir.path.clone()
+    } else {
+        from.to_string()
+    };
+    verify_bundle(&dir, keyring.unwrap_or(KEYRING_FILE)).and_then(|manifest| {
+        let names: Vec<&str> = manifest.packages.iter().map(|p| p.name.as_str()).collect();
+        let installed = installed_versions(&names)?;
+        for group in install_order(&manifest.packages) {
+            let to_install: Vec<String> = group
+                .iter()
+                .filter(|package| {
+                    if installed.get(&package.name) == Some(&package.version) {
+                        info!("{} {} is already installed", package.name, package.version);
+                        false
+                    } else {
+                        true
+                    }
+                })
+                .map(|package| format!("{}/{}", dir, package.file))
+                .collect();
+            let files: Vec<&str> = to_install.iter().map(|file| file.as_str()).collect();
+            install_files(&files)?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,314 @@
+use snafu::ResultExt;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+
+// Markers which delimit the block of config.txt which is managed by peach-config
+pub const MANAGED_BLOCK_BEGIN: &str = "# BEGIN PEACHCLOUD MANAGED BLOCK - do not edit";
+pub const MANAGED_BLOCK_END: &str = "# END PEACHCLOUD MANAGED BLOCK";
+
+/// A single setting in config.txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    // dtparam=<value> e.g. dtparam=i2c_arm=on
+    DtParam(String),
+    // dtoverlay=<value> e.g. dtoverlay=i2c-rtc,ds3231
+    DtOverlay(String),
+    // any other <key>=<value> setting e.g. enable_uart=1
+    Setting { key: String, value: String },
+}
+
+impl Directive {
+    /// Parses a line of config.txt, returning None if the line is not a key=value setting
+    pub fn parse(line: &str) -> Option<Directive> {
+        let (key, value) = line.trim().split_once('=')?;
+        let (key, value) = (key.trim(), value.trim().to_string());
+        if key.is_empty() || key.starts_with('#') {
+            return None;
+        }
+        match key {
+            "dtparam" => Some(Directive::DtParam(value)),
+            "dtoverlay" => Some(Directive::DtOverlay(value)),
+            _ => Some(Directive::Setting {
+                key: key.to_string(),
+                value,
+            }),
+        }
+    }
+
+    /// Returns true if the directive was written to config.txt by earlier versions of peach-config,
+    /// which copied a complete config.txt from the conf dir instead of managing a block
+    fn is_legacy(&self) -> bool {
+        match self {
+            Directive::DtParam(value) => value == "i2c_arm=on",
+            Directive::DtOverlay(value) => value == "i2c-rtc" || value.starts_with("i2c-rtc,"),
+            Directive::Setting { key, value } => {
+                (key == "upstream_kernel" && value == "1")
+                    || (key == "device_tree_overlay" && value == "overlays/mygpio.dtbo")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Directive::DtParam(value) => write!(f, "dtparam={}", value),
+            Directive::DtOverlay(value) => write!(f, "dtoverlay={}", value),
+            Directive::Setting { key, value } => write!(f, "{}={}", key, value),
+        }
+    }
+}
+
+/// A line of config.txt outside of the managed block, kept verbatim so that
+/// user settings are written back exactly as they were found
+#[derive(Debug)]
+struct Line {
+    raw: String,
+    directive: Option<Directive>,
+    // true if the line applies to all boards, i.e. it is before the first
+    // section header or after an [all] section header
+    global: bool,
+}
+
+/// Parsed representation of /boot/firmware/config.txt
+///
+/// Lines outside of the PeachCloud managed block are left untouched,
+/// while the directives inside of the managed block are replaced as a whole.
+#[derive(Debug, Default)]
+pub struct ConfigTxt {
+    lines: Vec<Line>,
+    managed: Vec<Directive>,
+    // true if the file has a managed block, even an empty one, which records that the
+    // directives of earlier versions of peach-config have been migrated into it
+    has_managed_block: bool,
+}
+
+impl ConfigTxt {
+    /// Parses the contents of a config.txt file
+    pub fn parse(contents: &str) -> ConfigTxt {
+        let mut config = ConfigTxt::default();
+        let mut in_managed_block = false;
+        let mut global = true;
+        for raw in contents.lines() {
+            let trimmed = raw.trim();
+            if trimmed == MANAGED_BLOCK_BEGIN {
+                in_managed_block = true;
+                config.has_managed_block = true;
+            } else if trimmed == MANAGED_BLOCK_END {
+                in_managed_block = false;
+            } else if in_managed_block {
+                // section headers inside of the managed block are written by peach-config
+                if let Some(directive) = Directive::parse(trimmed) {
+                    config.managed.push(directive);
+                }
+            } else {
+                if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                    global = trimmed == "[all]";
+                }
+                config.lines.push(Line {
+                    raw: raw.to_string(),
+                    directive: Directive::parse(trimmed),
+                    global,
+                });
+            }
+        }
+        config
+    }
+
+    /// Loads and parses config.txt from the given path,
+    /// returning an empty configuration if the file does not exist
+    pub fn load(path: &str) -> Result<ConfigTxt, PeachConfigError> {
+        if !Path::new(path).exists() {
+            return Ok(ConfigTxt::default());
+        }
+        let contents = fs::read_to_string(path).context(FileReadError {
+            file: path.to_string(),
+        })?;
+        Ok(ConfigTxt::parse(&contents))
+    }
+
+    /// Writes the configuration to the given path
+    pub fn save(&self, path: &str) -> Result<(), PeachConfigError> {
+        fs::write(path, self.to_string()).context(FileWriteError {
+            file: path.to_string(),
+        })?;
+        Ok(())
+    }
+
+    /// Replaces the directives of the managed block, which is written even if it is empty.
+    ///
+    /// Lines outside of the managed block are only changed when the block is first written,
+    /// so that settings the user adds to config.txt later on are left in place. At that point,
+    /// directives written by earlier versions of peach-config (which overwrote config.txt)
+    /// are removed, whether or not they are still wanted, so that changing or disabling the
+    /// i2c and real-time clock settings takes effect. Other lines which apply to all boards and
+    /// are identical to one of the given directives are removed as well, so that they are
+    /// adopted into the managed block instead of being duplicated.
+    pub fn set_managed_directives(&mut self, directives: Vec<Directive>) {
+        if !self.has_managed_block {
+            self.lines.retain(|line| match &line.directive {
+                Some(directive) => {
+                    !(directive.is_legacy() || line.global && directives.contains(directive))
+                }
+                None => true,
+            });
+        }
+        self.managed = directives;
+        self.has_managed_block = true;
+    }
+
+    /// Removes the managed block along with its directives, leaving the other lines untouched
+    pub fn remove_managed_block(&mut self) {
+        self.managed.clear();
+        self.has_managed_block = false;
+    }
+}
+
+impl fmt::Display for ConfigTxt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line.raw)?;
+        }
+        if self.has_managed_block {
+            if self
+                .lines
+                .last()
+                .is_some_and(|line| !line.raw.trim().is_empty())
+            {
+                writeln!(f)?;
+            }
+            writeln!(f, "{}", MANAGED_BLOCK_BEGIN)?;
+            // reset any conditional section so that the managed directives apply to all boards
+            writeln!(f, "[all]")?;
+            for directive in &self.managed {
+                writeln!(f, "{}", directive)?;
+            }
+            writeln!(f, "{}", MANAGED_BLOCK_END)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY_CONFIG_TXT: &str = "\
+# For more options and information see
+# http://rpf.io/configtxt
+arm_control=0x200
+enable_uart=1
+device_tree_overlay=overlays/mygpio.dtbo
+dtparam=i2c_arm=on
+dtoverlay=i2c-rtc,ds1307
+
+[pi4]
+dtoverlay=vc4-fkms-v3d
+";
+
+    #[test]
+    fn parse_and_display_round_trip() {
+        let contents = format!(
+            "arm_control=0x200\n# a comment\n\n[pi4]\ndtoverlay=vc4-fkms-v3d\n\n{}\n[all]\ndtparam=i2c_arm=on\ndtoverlay=i2c-rtc,ds3231\n{}\n",
+            MANAGED_BLOCK_BEGIN, MANAGED_BLOCK_END
+        );
+        let config = ConfigTxt::parse(&contents);
+        assert_eq!(
+            config.managed,
+            vec![
+                Directive::DtParam("i2c_arm=on".to_string()),
+                Directive::DtOverlay("i2c-rtc,ds3231".to_string()),
+            ]
+        );
+        assert_eq!(config.to_string(), contents);
+        assert_eq!(ConfigTxt::parse(&config.to_string()).to_string(), contents);
+    }
+
+    #[test]
+    fn directive_parse() {
+        assert_eq!(
+            Directive::parse(" dtparam = i2c_arm=on "),
+            Some(Directive::DtParam("i2c_arm=on".to_string()))
+        );
+        assert_eq!(
+            Directive::parse("enable_uart=1"),
+            Some(Directive::Setting {
+                key: "enable_uart".to_string(),
+                value: "1".to_string(),
+            })
+        );
+        assert_eq!(Directive::parse("#dtparam=i2c_arm=on"), None);
+        assert_eq!(Directive::parse("[pi4]"), None);
+        assert_eq!(Directive::parse("initramfs initrd.img"), None);
+    }
+
+    #[test]
+    fn legacy_directives_are_adopted() {
+        let mut config = ConfigTxt::parse(LEGACY_CONFIG_TXT);
+        config.set_managed_directives(vec![
+            Directive::DtParam("i2c_arm=on".to_string()),
+            Directive::DtOverlay("i2c-rtc,ds3231".to_string()),
+        ]);
+        let contents = config.to_string();
+        assert_eq!(contents.matches("dtparam=i2c_arm=on").count(), 1);
+        assert_eq!(contents.matches("dtoverlay=i2c-rtc").count(), 1);
+        assert!(contents.contains("dtoverlay=i2c-rtc,ds3231"));
+        assert!(!contents.contains("device_tree_overlay"));
+        assert!(contents.contains("enable_uart=1\n"));
+        assert!(contents.contains("[pi4]\ndtoverlay=vc4-fkms-v3d\n"));
+    }
+
+    #[test]
+    fn legacy_directives_are_removed_when_disabled() {
+        let mut config = ConfigTxt::parse(LEGACY_CONFIG_TXT);
+        config.set_managed_directives(Vec::new());
+        let contents = config.to_string();
+        assert!(!contents.contains("i2c"));
+        assert!(!contents.contains("device_tree_overlay"));
+        assert!(contents.contains("arm_control=0x200\n"));
+        // the empty block records that the legacy directives have been migrated
+        assert!(contents.ends_with(&format!(
+            "{}\n[all]\n{}\n",
+            MANAGED_BLOCK_BEGIN, MANAGED_BLOCK_END
+        )));
+    }
+
+    #[test]
+    fn user_lines_are_kept_once_the_block_exists() {
+        let mut config = ConfigTxt::parse(LEGACY_CONFIG_TXT);
+        config.set_managed_directives(Vec::new());
+        // the user enables i2c themselves after the legacy directives were migrated
+        let contents = format!("dtparam=i2c_arm=on\n{}", config);
+        let mut config = ConfigTxt::parse(&contents);
+        config.set_managed_directives(vec![Directive::DtParam("i2c_arm=on".to_string())]);
+        config.set_managed_directives(Vec::new());
+        let contents = config.to_string();
+        assert!(contents.starts_with("dtparam=i2c_arm=on\n"));
+        assert_eq!(contents.matches("i2c_arm").count(), 1);
+    }
+
+    #[test]
+    fn removing_the_block_keeps_other_lines() {
+        let contents = format!(
+            "enable_uart=1\n\n{}\n[all]\ndtparam=i2c_arm=on\n{}\n",
+            MANAGED_BLOCK_BEGIN, MANAGED_BLOCK_END
+        );
+        let mut config = ConfigTxt::parse(&contents);
+        config.remove_managed_block();
+        assert_eq!(config.to_string(), "enable_uart=1\n\n");
+    }
+
+    #[test]
+    fn user_directives_outside_of_the_block_are_kept() {
+        let contents = "[pi4]\ndtparam=audio=on\n[all]\ndtparam=audio=on\n";
+        let mut config = ConfigTxt::parse(contents);
+        config.set_managed_directives(vec![Directive::DtParam("audio=on".to_string())]);
+        // only the line which applies to all boards is adopted into the managed block
+        assert!(config
+            .to_string()
+            .starts_with("[pi4]\ndtparam=audio=on\n[all]\n\n"));
+    }
+}
@@ -23,3 +23,16 @@ pub const SERVICES: [&str; 11] = [
 // the values in the hardware_config.json are a log of what peach-config configured
 // whereas the values in config.yml can be manually modified if needed
 pub const HARDWARE_CONFIG_FILE: &str = "/var/lib/peachcloud/hardware_config.json";
+
+// File containing the hostname of the running system
+pub const HOSTNAME_FILE: &str = "/proc/sys/kernel/hostname";
+
+// File path to where the versions of the services installed before the last update are stored,
+// which peach-config update rollback reinstalls
+pub const ROLLBACK_FILE: &str = "/var/lib/peachcloud/update_rollback.json";
+
+// File path to where settings chosen after setup, such as the release channel, are stored
+pub const SETTINGS_FILE: &str = "/var/lib/peachcloud/settings.json";
+
+// File path to the log of updates, which has a json entry per line
+pub const HISTORY_FILE: &str = "/var/lib/peachcloud/update_history.jsonl";
@@ -0,0 +1,198 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// A Debian package version of the form [epoch:]upstream_version[-debian_revision],
+/// ordered as described in the "Version" section of the Debian Policy Manual.
+#[derive(Debug, Clone)]
+pub struct DebianVersion {
+    pub epoch: u64,
+    pub upstream: String,
+    pub revision: String,
+}
+
+impl FromStr for DebianVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("empty version string".to_string());
+        }
+        let (epoch, rest) = match s.split_once(':') {
+            Some((epoch, rest)) => (
+                epoch
+                    .parse()
+                    .map_err(|_| format!("invalid epoch in version: {}", s))?,
+                rest,
+            ),
+            None => (0, s),
+        };
+        // the revision is everything after the last hyphen, if there is one
+        let (upstream, revision) = match rest.rsplit_once('-') {
+            Some((upstream, revision)) => (upstream, revision),
+            None => (rest, ""),
+        };
+        if upstream.is_empty() || !upstream.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("upstream version must start with a digit: {}", s));
+        }
+        Ok(DebianVersion {
+            epoch,
+            upstream: upstream.to_string(),
+            revision: revision.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for DebianVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.epoch > 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        write!(f, "{}", self.upstream)?;
+        if !self.revision.is_empty() {
+            write!(f, "-{}", self.revision)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sort weight of a character in the non-digit parts of a version: a tilde sorts before
+/// anything, even the end of the string, and letters sort before all other characters.
+fn char_order(c: Option<char>) -> i32 {
+    match c {
+        Some('~') => -1,
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+/// Compares an upstream version or revision, alternating between comparing
+/// non-digit prefixes lexically and digit prefixes numerically
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        // compare the non-digit prefixes
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let ac = char_order(a.get(i).map(|&c| c as char));
+            let bc = char_order(b.get(j).map(|&c| c as char));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+        // compare the digit prefixes, ignoring leading zeroes
+        while i < a.len() && a[i] == b'0' {
+            i += 1;
+        }
+        while j < b.len() && b[j] == b'0' {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while i < a.len() && a[i].is_ascii_digit() && j < b.len() && b[j].is_ascii_digit() {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        // the longer number is larger, otherwise the first differing digit decides
+        if i < a.len() && a[i].is_ascii_digit() {
+            return Ordering::Greater;
+        }
+        if j < b.len() && b[j].is_ascii_digit() {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+impl Ord for DebianVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| compare_part(&self.upstream, &other.upstream))
+            .then_with(|| compare_part(&self.revision, &other.revision))
+    }
+}
+
+impl PartialOrd for DebianVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for DebianVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DebianVersion {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> DebianVersion {
+        s.parse().unwrap()
+    }
+
+    fn assert_less(a: &str, b: &str) {
+        assert!(version(a) < version(b), "expected {} < {}", a, b);
+        assert!(version(b) > version(a), "expected {} > {}", b, a);
+    }
+
+    #[test]
+    fn parse_and_display() {
+        let v = version("2:1.0.3-rc1-2");
+        assert_eq!(v.epoch, 2);
+        assert_eq!(v.upstream, "1.0.3-rc1");
+        assert_eq!(v.revision, "2");
+        assert_eq!(v.to_string(), "2:1.0.3-rc1-2");
+        assert_eq!(version("0.6.1").to_string(), "0.6.1");
+        assert!("".parse::<DebianVersion>().is_err());
+        assert!("a1.0".parse::<DebianVersion>().is_err());
+        assert!("x:1.0".parse::<DebianVersion>().is_err());
+    }
+
+    #[test]
+    fn epoch_ordering() {
+        assert_less("9.9", "1:0.1");
+        assert_less("1:2.0", "2:1.0");
+        assert_eq!(version("0:1.0"), version("1.0"));
+    }
+
+    #[test]
+    fn tilde_ordering() {
+        assert_less("1.0~rc1", "1.0");
+        assert_less("1.0~~", "1.0~");
+        assert_less("1.0~rc1", "1.0~rc2");
+        assert_less("1.0", "1.0+b1");
+        assert_less("1.0~", "1.0a");
+    }
+
+    #[test]
+    fn revision_ordering() {
+        assert_less("1.0-1", "1.0-2");
+        assert_less("1.0-9", "1.0-10");
+        assert_less("1.0", "1.0-1");
+        assert_less("1.0-1", "1.0.1-1");
+        assert_less("1.0-1~bpo1", "1.0-1");
+    }
+
+    #[test]
+    fn numeric_ordering() {
+        assert_less("0.6.9", "0.6.10");
+        assert_eq!(version("1.01"), version("1.1"));
+        assert_less("1.0a", "1.0b");
+        assert_less("1.0b", "1.0.1");
+    }
+}
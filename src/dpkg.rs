@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::error::{FileReadError, PeachConfigError};
+
+// Database of the install state of every package known to dpkg
+pub const DPKG_STATUS_FILE: &str = "/var/lib/dpkg/status";
+
+/// The state of a package as recorded in the dpkg status database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageStatus {
+    pub name: String,
+    // the selection state, e.g. "install", "hold" or "deinstall"
+    pub want: String,
+    // "ok" or "reinstreq" if the package is broken and needs reinstalling
+    pub flag: String,
+    // the install state, e.g. "installed", "config-files" or "half-configured"
+    pub state: String,
+    pub version: Option<String>,
+    pub architecture: Option<String>,
+    // name of the source package, which is only recorded by dpkg
+    // if it differs from the name of the binary package, without
+    // the source version dpkg records if that differs as well
+    pub source: Option<String>,
+}
+
+impl PackageStatus {
+    /// Returns true if the package is fully installed and configured
+    pub fn is_installed(&self) -> bool {
+        self.state == "installed"
+    }
+}
+
+/// Parses a file in the Debian control-file format (as used by dpkg/status and .deb control
+/// files) into a list of paragraphs, each a map of field name to value.
+///
+/// Continuation lines, which start with whitespace, are appended to the value of the previous
+/// field separated by a newline.
+pub fn parse_control_file(contents: &str) -> Vec<HashMap<String, String>> {
+    let mut paragraphs = Vec::new();
+    let mut paragraph: HashMap<String, String> = HashMap::new();
+    let mut last_field: Option<String> = None;
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            if !paragraph.is_empty() {
+                paragraphs.push(std::mem::take(&mut paragraph));
+            }
+            last_field = None;
+        } else if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = last_field.as_ref().and_then(|f| paragraph.get_mut(f)) {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        } else if let Some((field, value)) = line.split_once(':') {
+            let field = field.trim().to_string();
+            paragraph.insert(field.clone(), value.trim().to_string());
+            last_field = Some(field);
+        }
+    }
+    if !paragraph.is_empty() {
+        paragraphs.push(paragraph);
+    }
+    paragraphs
+}
+
+/// Converts a paragraph of the dpkg status database into a PackageStatus,
+/// returning None if the paragraph has no Package or valid Status field
+fn package_status(mut paragraph: HashMap<String, String>) -> Option<PackageStatus> {
+    let name = paragraph.remove("Package")?;
+    let status = paragraph.remove("Status")?;
+    let mut words = status.split_whitespace();
+    let (want, flag, state) = (words.next()?, words.next()?, words.next()?);
+    Some(PackageStatus {
+        name,
+        want: want.to_string(),
+        flag: flag.to_string(),
+        state: state.to_string(),
+        version: paragraph.remove("Version"),
+        architecture: paragraph.remove("Architecture"),
+        // the field has the form "name" or "name (version)"
+        source: paragraph
+            .remove("Source")
+            .and_then(|source| source.split_whitespace().next().map(str::to_string)),
+    })
+}
+
+/// Reads the dpkg status database and returns the status of the given packages
+/// which are known to dpkg, keyed by package name.
+///
+/// Packages which dpkg has never installed are not included.
+pub fn read_package_status(
+    names: &[&str],
+) -> Result<HashMap<String, PackageStatus>, PeachConfigError> {
+    let contents = fs::read_to_string(DPKG_STATUS_FILE).context(FileReadError {
+        file: DPKG_STATUS_FILE.to_string(),
+    })?;
+    let statuses = parse_control_file(&contents)
+        .into_iter()
+        .filter(|paragraph| {
+            paragraph
+                .get("Package")
+                .is_some_and(|name| names.contains(&name.as_str()))
+        })
+        .filter_map(package_status)
+        .map(|status| (status.name.clone(), status))
+        .collect();
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = "\
+Package: peach-web
+Status: install ok installed
+Version: 0.6.1
+Architecture: arm64
+Description: web interface of PeachCloud
+ Serves the web interface
+ .
+ over http
+
+Package: peach-oled
+Status: deinstall ok config-files
+Version: 0.2.0
+Architecture: arm64
+Source: peach-hardware (0.2.0-1)
+
+Package: peach-menu
+Status: install ok half-configured
+Source: peach-hardware
+
+Package: peach-broken
+Status: install reinstreq
+";
+
+    fn statuses() -> HashMap<String, PackageStatus> {
+        parse_control_file(STATUS)
+            .into_iter()
+            .filter_map(package_status)
+            .map(|status| (status.name.clone(), status))
+            .collect()
+    }
+
+    #[test]
+    fn paragraphs_and_continuation_lines_are_parsed() {
+        let paragraphs = parse_control_file(STATUS);
+        assert_eq!(paragraphs.len(), 4);
+        assert_eq!(paragraphs[0]["Package"], "peach-web");
+        assert_eq!(
+            paragraphs[0]["Description"],
+            "web interface of PeachCloud\nServes the web interface\n.\nover http"
+        );
+        assert_eq!(paragraphs[1]["Source"], "peach-hardware (0.2.0-1)");
+        // blank lines at the start and end and between paragraphs are ignored
+        let padded = format!("\n\n{}\n\n", STATUS);
+        assert_eq!(parse_control_file(&padded), paragraphs);
+    }
+
+    #[test]
+    fn package_status_is_read() {
+        let statuses = statuses();
+        let web = &statuses["peach-web"];
+        assert!(web.is_installed());
+        assert_eq!(web.want, "install");
+        assert_eq!(web.flag, "ok");
+        assert_eq!(web.version.as_deref(), Some("0.6.1"));
+        assert_eq!(web.architecture.as_deref(), Some("arm64"));
+        assert_eq!(web.source, None);
+
+        let oled = &statuses["peach-oled"];
+        assert!(!oled.is_installed());
+        assert_eq!(oled.state, "config-files");
+        assert_eq!(oled.source.as_deref(), Some("peach-hardware"));
+    }
+
+    #[test]
+    fn missing_fields_are_none() {
+        let statuses = statuses();
+        let menu = &statuses["peach-menu"];
+        assert_eq!(menu.state, "half-configured");
+        assert_eq!(menu.version, None);
+        assert_eq!(menu.architecture, None);
+        assert_eq!(menu.source.as_deref(), Some("peach-hardware"));
+        // a status without all three words is not valid
+        assert!(!statuses.contains_key("peach-broken"));
+        let no_status = parse_control_file("Package: peach-stats\nVersion: 0.1.0\n");
+        assert_eq!(package_status(no_status[0].clone()), None);
+    }
+}
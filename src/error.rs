@@ -1,5 +1,4 @@
 #![allow(clippy::nonstandard_macro_braces)]
-pub use snafu::ResultExt;
 use snafu::Snafu;
 
 #[derive(Debug, Snafu)]
@@ -30,6 +29,48 @@ pub enum PeachConfigError {
     },
     #[snafu(display("Error serializing json: {}", source))]
     SerdeError { source: serde_json::Error },
+    #[snafu(display("Failed to detect board: {}", msg))]
+    BoardDetectionError { msg: String },
+    #[snafu(display("Invalid version of {}: {}", package, msg))]
+    VersionParseError { msg: String, package: String },
+    #[snafu(display("Health check failed for: {}", services))]
+    HealthCheckError { services: String },
+    #[snafu(display("Failed to roll back update: {}", msg))]
+    RollbackError { msg: String },
+    #[snafu(display(
+        "PeachCloud repository key has fingerprint {}, expected {}",
+        found,
+        expected
+    ))]
+    KeyVerificationError { expected: String, found: String },
+    #[snafu(display("Invalid offline bundle: {}", msg))]
+    BundleError { msg: String },
+    #[snafu(display(
+        "peach-config is already running (pid {}: {}), use --wait to wait for it to finish",
+        pid,
+        command
+    ))]
+    LockError { pid: u32, command: String },
+    #[snafu(display(
+        "\"{}\" gave up after waiting {}s for another package manager to release the dpkg lock",
+        command,
+        waited
+    ))]
+    AptLockTimeout { command: String, waited: u64 },
+    #[snafu(display("\"{}\" could not find a package. {}", command, msg))]
+    AptNotFoundError { command: String, msg: String },
+    #[snafu(display("\"{}\" could not reach the apt repository. {}", command, msg))]
+    AptNetworkError { command: String, msg: String },
+    #[snafu(display("\"{}\" could not verify the apt repository. {}", command, msg))]
+    AptSignatureError { command: String, msg: String },
+    #[snafu(display("\"{}\" could not resolve dependencies. {}", command, msg))]
+    AptDependencyError { command: String, msg: String },
+    #[snafu(display(
+        "\"{}\" failed because dpkg was interrupted, run dpkg --configure -a. {}",
+        command,
+        msg
+    ))]
+    DpkgInterruptedError { command: String, msg: String },
 }
 
 impl From<std::io::Error> for PeachConfigError {
@@ -1,62 +1,82 @@
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 use std::collections::HashMap;
 use std::fs;
 
-use crate::constants::HARDWARE_CONFIG_FILE;
+use crate::apt::installed_versions;
+use crate::board::Board;
+use crate::constants::{HARDWARE_CONFIG_FILE, HOSTNAME_FILE, SERVICES};
+use crate::dpkg::{read_package_status, PackageStatus};
 use crate::error::{FileReadError, FileWriteError, PeachConfigError};
-use crate::utils::get_output;
+use crate::os_release::{read_os_release, OsRelease};
+use crate::pinning::get_pinned_services;
+use crate::probe_hardware::{probe_hardware, ProbedHardware};
+use crate::settings::{load_settings, Channel};
+use crate::utils::now_timestamp;
 use crate::RtcOption;
 
 /// Returns a HashMap<String, String> of all the peach-packages which are currently installed
 /// mapped to their version number e.g. { "peach-probe": "1.2.0", "peach-network": "1.4.0" }
+///
+/// Only packages in SERVICES which dpkg reports as fully installed are included,
+/// removed packages whose configuration files remain are not.
 pub fn get_currently_installed_microservices() -> Result<HashMap<String, String>, PeachConfigError>
 {
-    // gets a list of all packages currently installed with dpkg
-    let packages = get_output(&["dpkg", "-l"])?;
+    installed_versions(&SERVICES)
+}
 
-    // this regex matches packages which contain the word peach in them
-    // and has two match groups
-    // 1. the first match group gets the package name
-    // 2. the second match group gets the version number of the package
-    let re: Regex = Regex::new(r"\S+\s+(\S*peach\S+)\s+(\S+).*\n").unwrap();
+/// Version of the manifest format, which is incremented whenever a field is removed or
+/// changes meaning. Adding a field does not change the version. Manifests generated before
+/// the version was introduced have no schema_version field and are read as version 0.
+pub const MANIFEST_SCHEMA_VERSION: u32 = 1;
 
-    // the following iterator, iterates through the captures matched via the regex
-    // and for each capture, creates a value in the hash map,
-    //  which maps the name of the package, to its version number
-    // e.g. { "peach-probe": "1.2.0", "peach-network": "1.4.0" }
-    let peach_packages: HashMap<String, String> = re
-        .captures_iter(&packages)
-        .filter_map(|cap| {
-            let groups = (cap.get(1), cap.get(2));
-            match groups {
-                (Some(package), Some(version)) => {
-                    Some((package.as_str().to_string(), version.as_str().to_string()))
-                }
-                _ => None,
-            }
-        })
-        .collect();
-
-    // finally the hashmap of packages and version numbers is returned
-    Ok(peach_packages)
-}
+/// JSON Schema describing the manifest, printed by `peach-config manifest --schema`
+pub const MANIFEST_SCHEMA: &str = include_str!("manifest_schema.json");
 
 /// Output form of manifest
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
+    #[serde(default)]
+    schema_version: u32,
+    // time at which the manifest was generated, as an RFC 3339 timestamp in UTC
+    #[serde(default)]
+    generated_at: Option<String>,
+    // version of peach-config which generated the manifest
+    #[serde(default)]
+    peach_config_version: Option<String>,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(default)]
+    os_release: Option<OsRelease>,
     // packages is a map of {package_name: version}
     packages: HashMap<String, String>,
+    // the dpkg status of every PeachCloud package known to dpkg, including packages
+    // which are not fully installed, keyed by package name
+    #[serde(default)]
+    package_status: HashMap<String, PackageStatus>,
+    // map of {package_name: version} of services which are pinned at a version
+    #[serde(default)]
+    pinned: HashMap<String, String>,
+    // release channel which packages are installed from
+    #[serde(default)]
+    channel: Option<Channel>,
     hardware: Option<HardwareConfig>,
+    // hardware which was found attached to the device when the manifest was generated
+    #[serde(default)]
+    probed_hardware: Option<ProbedHardware>,
 }
 
 /// The form that hardware configs are saved in when peach-config setup runs successfully
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HardwareConfig {
-    // packages is a map of {package_name: version}
-    i2c: bool,
-    rtc: Option<RtcOption>,
+    // true if i2c was configured for the OLED display and buttons
+    pub i2c: bool,
+    // model of the real-time clock which was configured, if any
+    pub rtc: Option<RtcOption>,
+    // board which was detected (or selected with --board) when setup ran,
+    // None for configs saved by versions of peach-config without board detection
+    #[serde(default)]
+    pub board: Option<Board>,
 }
 
 /// Log which hardware settings were configured to a .json file
@@ -64,15 +84,17 @@ pub struct HardwareConfig {
 ///
 /// * `i2c` - a boolean flag, if true i2c will be configured
 /// * `rtc` - an optional enum, if supplied indicates which real-time-clock model
-/// is being used
+///   is being used
+/// * `board` - an optional enum, indicating which board the configuration was applied to
 ///
 /// Any error results in a PeachConfigError, otherwise the saved HardwareConfig object
 /// is returned.
 pub fn save_hardware_config(
     i2c: bool,
     rtc: Option<RtcOption>,
+    board: Option<Board>,
 ) -> Result<HardwareConfig, PeachConfigError> {
-    let hardware_config = HardwareConfig { i2c, rtc };
+    let hardware_config = HardwareConfig { i2c, rtc, board };
 
     let json_str = serde_json::to_string(&hardware_config)?;
 
@@ -84,10 +106,11 @@ pub fn save_hardware_config(
 }
 
 /// Load the hardware configs that were saved from the last successful run of peach-config setup
+/// or peach-config hardware set
 ///
 /// Returns an Ok(Some<HardwareConfg>) containing the configuration if one is found,
 /// and returns Ok(None) if no hardware configuration was found.
-fn load_hardware_config() -> Result<Option<HardwareConfig>, PeachConfigError> {
+pub fn load_hardware_config() -> Result<Option<HardwareConfig>, PeachConfigError> {
     // if there is no hardware_config, return None
     let hardware_config_exists = std::path::Path::new(HARDWARE_CONFIG_FILE).exists();
     if !hardware_config_exists {
@@ -104,16 +127,49 @@ fn load_hardware_config() -> Result<Option<HardwareConfig>, PeachConfigError> {
 }
 
 /// Outputs a Manifest in json form to stdout
-/// which contains the currently installed peach packages
-/// as well as the hardware configuration of the last run of peach-config setup.
+/// which contains the schema version, when and where it was generated,
+/// the currently installed peach packages,
+/// the hardware configuration of the last run of peach-config setup,
+/// and the hardware which is currently attached to the device.
 pub fn generate_manifest() -> Result<(), PeachConfigError> {
+    let manifest = build_manifest()?;
+    let output = serde_json::to_string(&manifest)?;
+    println!("{}", output);
+    Ok(())
+}
+
+/// Builds a Manifest describing the running system
+pub fn build_manifest() -> Result<Manifest, PeachConfigError> {
     let packages = get_currently_installed_microservices()?;
     let hardware_config_option = load_hardware_config()?;
     let manifest = Manifest {
+        schema_version: MANIFEST_SCHEMA_VERSION,
+        generated_at: Some(now_timestamp()),
+        peach_config_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+        hostname: fs::read_to_string(HOSTNAME_FILE)
+            .ok()
+            .map(|hostname| hostname.trim().to_string()),
+        os_release: read_os_release(),
         packages,
+        package_status: read_package_status(&SERVICES)?,
+        pinned: get_pinned_services()?,
+        channel: Some(load_settings()?.channel),
         hardware: hardware_config_option,
+        probed_hardware: Some(probe_hardware()),
     };
-    let output = serde_json::to_string(&manifest)?;
-    println!("{}", output);
-    Ok(())
+    Ok(manifest)
+}
+
+/// Loads a Manifest which was previously saved to a file
+pub fn load_manifest(path: &str) -> Result<Manifest, PeachConfigError> {
+    let contents = fs::read_to_string(path).context(FileReadError {
+        file: path.to_string(),
+    })?;
+    let manifest: Manifest = serde_json::from_str(&contents)?;
+    Ok(manifest)
+}
+
+/// Outputs the JSON Schema of the Manifest to stdout
+pub fn print_manifest_schema() {
+    println!("{}", MANIFEST_SCHEMA.trim_end());
 }
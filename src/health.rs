@@ -0,0 +1,358 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::board::{board_profile, detect_board};
+use crate::constants::SERVICES;
+use crate::error::PeachConfigError;
+use crate::generate_manifest::{get_currently_installed_microservices, load_hardware_config};
+
+// Time to wait for an endpoint to accept a connection and to respond
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Services which drive the OLED display and buttons, and can only run if i2c is configured
+const I2C_SERVICES: [&str; 3] = ["peach-oled", "peach-menu", "peach-buttons"];
+
+/// An endpoint on localhost which a service answers on when it is running
+#[derive(Debug, Clone, Copy)]
+pub enum Endpoint {
+    // a JSON-RPC server, which is sent a ping request
+    JsonRpc { port: u16 },
+    // an HTTP server, which is sent a GET request for the given path
+    Http { port: u16, path: &'static str },
+}
+
+impl Endpoint {
+    fn url(&self) -> String {
+        match self {
+            Endpoint::JsonRpc { port } => format!("http://127.0.0.1:{}/", port),
+            Endpoint::Http { port, path } => format!("http://127.0.0.1:{}{}", port, path),
+        }
+    }
+}
+
+/// How a service is checked: the systemd unit it runs as and the endpoint it answers on
+pub struct ServiceCheck {
+    pub unit: Option<&'static str>,
+    pub endpoint: Option<Endpoint>,
+}
+
+/// Returns how the given service is checked. Services which do not run as a daemon,
+/// such as peach-config itself, have neither a unit nor an endpoint.
+pub fn service_check(service: &str) -> ServiceCheck {
+    let (unit, endpoint) = match service {
+        "peach-network" => (
+            Some("peach-network"),
+            Some(Endpoint::JsonRpc { port: 5110 }),
+        ),
+        "peach-oled" => (Some("peach-oled"), Some(Endpoint::JsonRpc { port: 5112 })),
+        "peach-stats" => (Some("peach-stats"), Some(Endpoint::JsonRpc { port: 5113 })),
+        // peach-web is served by nginx, which answers with a 502 if peach-web is down
+        "peach-web" => (
+            Some("peach-web"),
+            Some(Endpoint::Http {
+                port: 80,
+                path: "/",
+            }),
+        ),
+        "peach-menu" => (Some("peach-menu"), None),
+        "peach-buttons" => (Some("peach-buttons"), None),
+        "peach-monitor" => (Some("peach-monitor"), None),
+        _ => (None, None),
+    };
+    ServiceCheck { unit, endpoint }
+}
+
+/// Outcome of the health check of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    // the service is not installed or has nothing to check
+    Skipped,
+}
+
+/// Result of the health check of a single service
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub service: String,
+    pub unit: Option<String>,
+    // whether the systemd unit is active, None if it was not checked
+    pub unit_active: Option<bool>,
+    pub endpoint: Option<String>,
+    // whether the endpoint responded successfully, None if it was not probed
+    pub endpoint_ok: Option<bool>,
+    // the response status or connection error of the endpoint
+    pub endpoint_detail: Option<String>,
+    pub status: CheckStatus,
+}
+
+/// Output form of peach-config health
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthReport {
+    // true if no check failed
+    pub healthy: bool,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    /// Returns the names of the services whose check failed
+    pub fn failed_services(&self) -> Vec<&str> {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Fail)
+            .map(|check| check.service.as_str())
+            .collect()
+    }
+
+    /// Returns a HealthCheckError naming the failed services if any check failed
+    pub fn ensure_healthy(&self) -> Result<(), PeachConfigError> {
+        if self.healthy {
+            Ok(())
+        } else {
+            Err(PeachConfigError::HealthCheckError {
+                services: self.failed_services().join(", "),
+            })
+        }
+    }
+
+    /// Returns the names of the services whose check failed,
+    /// but did not fail in the given earlier report
+    pub fn regressed_services(&self, baseline: &HealthReport) -> Vec<&str> {
+        let failed_before = baseline.failed_services();
+        self.failed_services()
+            .into_iter()
+            .filter(|service| !failed_before.contains(service))
+            .collect()
+    }
+
+    /// Returns a HealthCheckError naming the failed services if any service failed its check
+    /// which did not fail in the given earlier report. Services which were already failing
+    /// are logged, but are not caused by what happened in between, e.g. an update.
+    pub fn ensure_no_regressions(&self, baseline: &HealthReport) -> Result<(), PeachConfigError> {
+        let regressed = self.regressed_services(baseline);
+        let still_failing: Vec<&str> = self
+            .failed_services()
+            .into_iter()
+            .filter(|service| !regressed.contains(service))
+            .collect();
+        if !still_failing.is_empty() {
+            warn!(
+                "services which were already failing before: {}",
+                still_failing.join(", ")
+            );
+        }
+        if regressed.is_empty() {
+            Ok(())
+        } else {
+            Err(PeachConfigError::HealthCheckError {
+                services: regressed.join(", "),
+            })
+        }
+    }
+}
+
+/// Returns true if the given systemd unit is active
+pub fn is_unit_active(unit: &str) -> bool {
+    Command::new("systemctl")
+        .args(["is-active", "--quiet", unit])
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Sends a request to an endpoint on localhost and returns the HTTP status code of the response
+fn request_status(endpoint: &Endpoint) -> Result<u16, String> {
+    let request = match endpoint {
+        Endpoint::JsonRpc { .. } => {
+            let body = r#"{"jsonrpc":"2.0","method":"ping","id":1}"#;
+            format!(
+                "POST / HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        Endpoint::Http { path, .. } => format!(
+            "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n",
+            path
+        ),
+    };
+    let port = match endpoint {
+        Endpoint::JsonRpc { port } | Endpoint::Http { port, .. } => *port,
+    };
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let mut stream =
+        TcpStream::connect_timeout(&addr, ENDPOINT_TIMEOUT).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(ENDPOINT_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(ENDPOINT_TIMEOUT)))
+        .and_then(|_| stream.write_all(request.as_bytes()))
+        .map_err(|e| e.to_string())?;
+    // the status line has the form "HTTP/1.1 200 OK"
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .map_err(|e| e.to_string())?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("invalid response: {}", status_line.trim()))
+}
+
+/// Probes an endpoint, returning whether it responded successfully and a description of the response
+fn probe_endpoint(endpoint: &Endpoint) -> (bool, String) {
+    match request_status(endpoint) {
+        // a JSON-RPC server answers a ping with 200, while any page which is not
+        // a server error shows that the web server and what it proxies to are up
+        Ok(status) => {
+            let ok = match endpoint {
+                Endpoint::JsonRpc { .. } => status == 200,
+                Endpoint::Http { .. } => status < 500,
+            };
+            (ok, format!("HTTP {}", status))
+        }
+        Err(err) => (false, err),
+    }
+}
+
+/// Returns true if i2c was configured by setup or hardware set on a board which has an i2c bus.
+/// If the hardware configuration cannot be read, i2c is assumed to be configured.
+fn is_i2c_configured() -> bool {
+    let config = match load_hardware_config() {
+        Ok(Some(config)) => config,
+        Ok(None) => return false,
+        Err(err) => {
+            warn!(
+                "could not read hardware configuration, checking all services: {}",
+                err
+            );
+            return true;
+        }
+    };
+    if !config.i2c {
+        return false;
+    }
+    match config.board.map_or_else(detect_board, Ok) {
+        Ok(board) => board_profile(board).i2c_bus.is_some(),
+        Err(_) => true,
+    }
+}
+
+/// Checks a single service, skipping it if it is not installed
+fn check_service(service: &str, installed: bool) -> HealthCheck {
+    let ServiceCheck { unit, endpoint } = service_check(service);
+    let mut check = HealthCheck {
+        service: service.to_string(),
+        unit: unit.map(|unit| unit.to_string()),
+        unit_active: None,
+        endpoint: endpoint.map(|endpoint| endpoint.url()),
+        endpoint_ok: None,
+        endpoint_detail: None,
+        status: CheckStatus::Skipped,
+    };
+    if !installed || (unit.is_none() && endpoint.is_none()) {
+        return check;
+    }
+    let unit_active = unit.map(is_unit_active);
+    check.unit_active = unit_active;
+    if let Some(endpoint) = endpoint {
+        let (ok, detail) = probe_endpoint(&endpoint);
+        check.endpoint_ok = Some(ok);
+        check.endpoint_detail = Some(detail);
+    }
+    let passed = unit_active.unwrap_or(true) && check.endpoint_ok.unwrap_or(true);
+    check.status = if passed {
+        CheckStatus::Pass
+    } else {
+        CheckStatus::Fail
+    };
+    info!("health check {}: {:?}", service, check.status);
+    check
+}
+
+/// Checks every service in SERVICES which is installed. If the installed services
+/// cannot be read from dpkg, every service is checked. The services which drive the
+/// OLED display and buttons are skipped if i2c is not configured, as they cannot run
+/// without it.
+pub fn run_health_checks() -> HealthReport {
+    let installed = match get_currently_installed_microservices() {
+        Ok(installed) => Some(installed),
+        Err(err) => {
+            warn!("could not read installed services, checking all: {}", err);
+            None
+        }
+    };
+    let i2c = is_i2c_configured();
+    let checks: Vec<HealthCheck> = SERVICES
+        .iter()
+        .map(|service| {
+            let is_installed = match &installed {
+                Some(installed) => installed.contains_key(*service),
+                None => true,
+            };
+            if !i2c && I2C_SERVICES.contains(service) {
+                info!("health check {}: skipped, i2c is not configured", service);
+                return check_service(service, false);
+            }
+            check_service(service, is_installed)
+        })
+        .collect();
+    let healthy = checks.iter().all(|check| check.status != CheckStatus::Fail);
+    HealthReport { healthy, checks }
+}
+
+/// Formats an optional check for the table, "-" meaning it was not checked
+fn display_check(ok: Option<bool>, passed: &str, failed: &str) -> String {
+    match ok {
+        Some(true) => passed.to_string(),
+        Some(false) => failed.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// Prints the health checks as a table with a row per service
+pub fn print_health_table(report: &HealthReport) {
+    println!("{:<22} {:<10} {:<32} RESULT", "SERVICE", "UNIT", "ENDPOINT");
+    for check in &report.checks {
+        let endpoint = match (&check.endpoint, &check.endpoint_detail) {
+            (Some(url), Some(detail)) => format!("{} ({})", url, detail),
+            (Some(url), None) => url.clone(),
+            _ => "-".to_string(),
+        };
+        let result = match check.status {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skipped => "skipped",
+        };
+        println!(
+            "{:<22} {:<10} {:<32} {}",
+            check.service,
+            display_check(check.unit_active, "active", "inactive"),
+            endpoint,
+            result
+        );
+    }
+}
+
+/// Checks the health of the PeachCloud services and prints a report
+///
+/// # Arguments
+///
+/// * `json` - a bool, if true the report is printed as json instead of a table
+///
+/// Returns a HealthCheckError if any service failed its check.
+pub fn health(json: bool) -> Result<(), PeachConfigError> {
+    let report = run_health_checks();
+    if json {
+        println!("{}", serde_json::to_string(&report)?);
+    } else {
+        print_health_table(&report);
+    }
+    report.ensure_healthy()
+}
@@ -0,0 +1,187 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::constants::HISTORY_FILE;
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+use crate::generate_manifest::get_currently_installed_microservices;
+use crate::utils::now_timestamp;
+
+/// What caused the versions of the services to change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    Setup,
+    Update,
+    Rollback,
+    AutoUpdate,
+}
+
+/// Outcome of an entry of the update history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Success => write!(f, "success"),
+            Outcome::Failure => write!(f, "failure"),
+        }
+    }
+}
+
+/// A single entry of the update history
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    // time at which the update finished, as an RFC 3339 timestamp in UTC
+    pub timestamp: String,
+    pub trigger: Trigger,
+    // user who ran peach-config, as reported by sudo or the shell
+    pub user: Option<String>,
+    // maps of {package_name: version} before and after the update
+    pub before: HashMap<String, String>,
+    pub after: HashMap<String, String>,
+    pub result: Outcome,
+    pub error: Option<String>,
+}
+
+/// Returns the user who ran peach-config, preferring the user who invoked sudo
+fn invoking_user() -> Option<String> {
+    env::var("SUDO_USER").or_else(|_| env::var("USER")).ok()
+}
+
+/// Appends an entry to HISTORY_FILE
+pub fn append_history(entry: &HistoryEntry) -> Result<(), PeachConfigError> {
+    let line = serde_json::to_string(entry)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(HISTORY_FILE)
+        .context(FileWriteError {
+            file: HISTORY_FILE.to_string(),
+        })?;
+    writeln!(file, "{}", line).context(FileWriteError {
+        file: HISTORY_FILE.to_string(),
+    })?;
+    Ok(())
+}
+
+/// Runs the given update and records the installed versions of the services before and after it,
+/// along with its result, in the update history. The result of the update is returned, and
+/// failing to write the history only logs a warning.
+///
+/// # Arguments
+///
+/// * `trigger` - what caused the update
+/// * `run_update` - the update to run
+pub fn record_history<F>(trigger: Trigger, run_update: F) -> Result<(), PeachConfigError>
+where
+    F: FnOnce() -> Result<(), PeachConfigError>,
+{
+    let before = get_currently_installed_microservices().unwrap_or_default();
+    let result = run_update();
+    let entry = HistoryEntry {
+        timestamp: now_timestamp(),
+        trigger,
+        user: invoking_user(),
+        before,
+        after: get_currently_installed_microservices().unwrap_or_default(),
+        result: if result.is_ok() {
+            Outcome::Success
+        } else {
+            Outcome::Failure
+        },
+        error: result.as_ref().err().map(|err| err.to_string()),
+    };
+    if let Err(err) = append_history(&entry) {
+        warn!("failed to record the update in the update history: {}", err);
+    }
+    result
+}
+
+/// Loads every entry of the update history, oldest first,
+/// skipping lines which are not valid entries
+pub fn load_history() -> Result<Vec<HistoryEntry>, PeachConfigError> {
+    if !Path::new(HISTORY_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(HISTORY_FILE).context(FileReadError {
+        file: HISTORY_FILE.to_string(),
+    })?;
+    let entries = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    Ok(entries)
+}
+
+/// Describes the changes between the versions before and after an entry,
+/// e.g. "peach-web 1.0.0 -> 1.1.0, +peach-probe 0.2.0"
+fn describe_changes(entry: &HistoryEntry) -> String {
+    let names: BTreeSet<&String> = entry.before.keys().chain(entry.after.keys()).collect();
+    let changes: Vec<String> = names
+        .into_iter()
+        .filter_map(
+            |name| match (entry.before.get(name), entry.after.get(name)) {
+                (Some(before), Some(after)) if before != after => {
+                    Some(format!("{} {} -> {}", name, before, after))
+                }
+                (None, Some(after)) => Some(format!("+{} {}", name, after)),
+                (Some(before), None) => Some(format!("-{} {}", name, before)),
+                _ => None,
+            },
+        )
+        .collect();
+    if changes.is_empty() {
+        "no changes".to_string()
+    } else {
+        changes.join(", ")
+    }
+}
+
+/// Prints the update history, oldest first
+///
+/// # Arguments
+///
+/// * `json` - a bool, if true the entries are printed as a json array instead of a table
+pub fn print_history(json: bool) -> Result<(), PeachConfigError> {
+    let entries = load_history()?;
+    if json {
+        println!("{}", serde_json::to_string(&entries)?);
+        return Ok(());
+    }
+    println!(
+        "{:<21} {:<12} {:<10} {:<8} CHANGES",
+        "TIMESTAMP", "TRIGGER", "USER", "RESULT"
+    );
+    for entry in &entries {
+        let trigger = match entry.trigger {
+            Trigger::Setup => "setup",
+            Trigger::Update => "update",
+            Trigger::Rollback => "rollback",
+            Trigger::AutoUpdate => "auto-update",
+        };
+        println!(
+            "{:<21} {:<12} {:<10} {:<8} {}",
+            entry.timestamp,
+            trigger,
+            entry.user.as_deref().unwrap_or("-"),
+            entry.result,
+            describe_changes(entry)
+        );
+        if let Some(err) = &entry.error {
+            println!("{:<21} error: {}", "", err);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use crate::error::PeachConfigError;
+use crate::utils::get_output;
+
+/// Returns true if the device file for the given i2c bus exists
+pub fn bus_exists(bus: u8) -> bool {
+    Path::new(&format!("/dev/i2c-{}", bus)).exists()
+}
+
+/// Probes the given addresses of an i2c bus with i2cdetect and returns those which responded,
+/// including addresses which are already claimed by a kernel driver.
+///
+/// Each address is probed on its own with a read, rather than scanning the whole bus with the
+/// quick write probes i2cdetect uses by default, which can change the state of some devices.
+pub fn probe_addresses(bus: u8, addresses: &[u16]) -> Result<Vec<u16>, PeachConfigError> {
+    let mut responded = Vec::new();
+    for address in addresses {
+        let address = format!("0x{:02x}", address);
+        let output = get_output(&[
+            "i2cdetect",
+            "-y",
+            "-r",
+            &bus.to_string(),
+            &address,
+            &address,
+        ])?;
+        responded.extend(parse_i2cdetect(&output));
+    }
+    Ok(responded)
+}
+
+/// Parses the table printed by i2cdetect e.g.
+///
+/// ```text
+///      0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f
+/// 00:          -- -- -- -- -- -- -- -- -- -- -- -- --
+/// 60: -- -- -- -- -- -- -- -- UU -- -- -- -- -- -- --
+/// ```
+///
+/// where "--" is an address which did not respond and "UU" an address in use by a driver.
+fn parse_i2cdetect(output: &str) -> Vec<u16> {
+    let mut addresses = Vec::new();
+    for line in output.lines().skip(1) {
+        let (row, cells) = match line.split_once(':') {
+            Some(split) => split,
+            None => continue,
+        };
+        let base = match u16::from_str_radix(row.trim(), 16) {
+            Ok(base) => base,
+            Err(_) => continue,
+        };
+        // each cell is three characters wide, a space followed by two characters
+        let cells: Vec<char> = cells.chars().collect();
+        for (offset, cell) in cells.chunks(3).enumerate() {
+            let cell: String = cell.iter().collect();
+            let cell = cell.trim();
+            if !cell.is_empty() && cell != "--" {
+                addresses.push(base + offset as u16);
+            }
+        }
+    }
+    addresses
+}
+
+/// Reads a single byte register from the device at the given address
+pub fn read_register(bus: u8, address: u16, register: u8) -> Result<u8, PeachConfigError> {
+    let output = get_output(&[
+        "i2cget",
+        "-y",
+        "-f",
+        &bus.to_string(),
+        &format!("0x{:02x}", address),
+        &format!("0x{:02x}", register),
+    ])?;
+    u8::from_str_radix(output.trim().trim_start_matches("0x"), 16).map_err(|_| {
+        PeachConfigError::CmdError {
+            msg: format!("unexpected output: {}", output),
+            command: "i2cget".to_string(),
+        }
+    })
+}
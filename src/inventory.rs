@@ -0,0 +1,176 @@
+use crate::constants::{HISTORY_FILE, ROLLBACK_FILE, SETTINGS_FILE};
+use crate::error::PeachConfigError;
+use crate::setup_hardware::{ACTIVATE_RTC_SCRIPT, ACTIVATE_RTC_SERVICE};
+use crate::setup_peach_deb::{APT_PROXY_FILE, APT_SOURCE_FILE, KEYRING_FILE};
+use crate::utils::{cmd, conf};
+
+/// A file which setup copies from the conf dir to its place in the system
+#[derive(Debug, Clone, Copy)]
+pub struct ConfFile {
+    // path relative to the conf dir
+    pub conf: &'static str,
+    // path the file is copied to
+    pub path: &'static str,
+    // permissions set with chmod after copying, if any
+    pub mode: Option<&'static str>,
+    // owner set with chown after copying, if any
+    pub owner: Option<&'static str>,
+}
+
+impl ConfFile {
+    const fn new(conf: &'static str, path: &'static str) -> ConfFile {
+        ConfFile {
+            conf,
+            path,
+            mode: None,
+            owner: None,
+        }
+    }
+
+    /// Copies the file from the conf dir, overwriting the installed file, and sets its permissions
+    pub fn install(&self) -> Result<(), PeachConfigError> {
+        cmd(&["cp", &conf(self.conf), self.path])?;
+        if let Some(mode) = self.mode {
+            cmd(&["chmod", mode, self.path])?;
+        }
+        if let Some(owner) = self.owner {
+            cmd(&["chown", owner, self.path])?;
+        }
+        Ok(())
+    }
+}
+
+// User who administers the device
+pub const PEACH_USER: &str = "peach";
+
+// System users which the microservices run as
+pub const SERVICE_USERS: [&str; 7] = [
+    "peach-buttons",
+    "peach-menu",
+    "peach-monitor",
+    "peach-network",
+    "peach-oled",
+    "peach-stats",
+    "peach-web",
+];
+
+// Groups created by setup
+pub const GROUPS: [&str; 2] = ["peach", "gpio-user"];
+
+// Packages which conflict with systemd-networkd, held by setup so that apt does not reinstall them
+pub const HELD_PACKAGES: [&str; 8] = [
+    "ifupdown",
+    "dhcpcd5",
+    "isc-dhcp-client",
+    "isc-dhcp-common",
+    "rsyslog",
+    "openresolv",
+    "avahi-daemon",
+    "libnss-mdns",
+];
+
+pub const HOSTNAME: ConfFile = ConfFile::new("hostname", "/etc/hostname");
+pub const HOSTS: ConfFile = ConfFile::new("hosts", "/etc/hosts");
+
+pub const NGINX_SITE: ConfFile =
+    ConfFile::new("peach.conf", "/etc/nginx/sites-available/peach.conf");
+// Symlink which enables NGINX_SITE
+pub const NGINX_SITE_LINK: &str = "/etc/nginx/sites-enabled/peach.conf";
+
+// Allows the peach group to shut down and reboot the device
+pub const SUDOERS_FILE: ConfFile = ConfFile::new("shutdown", "/etc/sudoers.d/shutdown");
+
+// Interface files for systemd-networkd
+pub const WIRED_NETWORK: ConfFile = ConfFile::new(
+    "network/04-wired.network",
+    "/etc/systemd/network/04-wired.network",
+);
+pub const WLAN0_NETWORK: ConfFile = ConfFile::new(
+    "network/08-wlan0.network",
+    "/etc/systemd/network/08-wlan0.network",
+);
+pub const AP0_NETWORK: ConfFile = ConfFile::new(
+    "network/12-ap0.network",
+    "/etc/systemd/network/12-ap0.network",
+);
+
+// Wi-Fi client configuration, which holds the credentials of the networks the device joins
+pub const WLAN0_WPA_SUPPLICANT: ConfFile = ConfFile {
+    conf: "network/wpa_supplicant-wlan0.conf",
+    path: "/etc/wpa_supplicant/wpa_supplicant-wlan0.conf",
+    mode: Some("660"),
+    owner: Some("root:netdev"),
+};
+
+// Access point configuration, which holds the access point password
+pub const AP0_WPA_SUPPLICANT: ConfFile = ConfFile {
+    conf: "network/wpa_supplicant-ap0.conf",
+    path: "/etc/wpa_supplicant/wpa_supplicant-ap0.conf",
+    mode: Some("600"),
+    owner: None,
+};
+
+pub const COPY_WLAN_SCRIPT: ConfFile = ConfFile {
+    conf: "network/copy-wlan.sh",
+    path: "/usr/local/bin/copy-wlan.sh",
+    mode: Some("770"),
+    owner: None,
+};
+pub const COPY_WLAN_SERVICE: ConfFile = ConfFile::new(
+    "network/copy-wlan.service",
+    "/etc/systemd/system/copy-wlan.service",
+);
+pub const AP0_SERVICE: ConfFile = ConfFile::new(
+    "network/wpa_supplicant@ap0.service",
+    "/etc/systemd/system/wpa_supplicant@ap0.service",
+);
+pub const AP_AUTO_DEPLOY_SCRIPT: ConfFile =
+    ConfFile::new("ap_auto_deploy.sh", "/usr/local/bin/ap_auto_deploy");
+pub const AP_AUTO_DEPLOY_SERVICE: ConfFile = ConfFile::new(
+    "network/ap-auto-deploy.service",
+    "/etc/systemd/system/ap-auto-deploy.service",
+);
+pub const AP_AUTO_DEPLOY_TIMER: ConfFile = ConfFile::new(
+    "network/ap-auto-deploy.timer",
+    "/etc/systemd/system/ap-auto-deploy.timer",
+);
+
+// Units installed by setup which are enabled, and disabled again by uninstall
+pub const ENABLED_UNITS: [&str; 2] = ["copy-wlan.service", "activate-rtc.service"];
+
+// Files which uninstall removes. Networking configuration is not included,
+// so that a device stays reachable after PeachCloud is uninstalled.
+pub const UNINSTALLED_FILES: [&str; 11] = [
+    NGINX_SITE_LINK,
+    NGINX_SITE.path,
+    SUDOERS_FILE.path,
+    COPY_WLAN_SCRIPT.path,
+    COPY_WLAN_SERVICE.path,
+    AP0_SERVICE.path,
+    AP_AUTO_DEPLOY_SCRIPT.path,
+    AP_AUTO_DEPLOY_SERVICE.path,
+    AP_AUTO_DEPLOY_TIMER.path,
+    ACTIVATE_RTC_SCRIPT,
+    ACTIVATE_RTC_SERVICE,
+];
+
+// Files of the PeachCloud apt repository
+pub const APT_FILES: [&str; 3] = [APT_SOURCE_FILE, APT_PROXY_FILE, KEYRING_FILE];
+
+// Configuration files which factory-reset restores to the versions shipped in the conf dir
+pub const RESET_FILES: [ConfFile; 10] = [
+    HOSTNAME,
+    HOSTS,
+    NGINX_SITE,
+    SUDOERS_FILE,
+    WIRED_NETWORK,
+    WLAN0_NETWORK,
+    AP0_NETWORK,
+    WLAN0_WPA_SUPPLICANT,
+    AP0_WPA_SUPPLICANT,
+    AP0_SERVICE,
+];
+
+// Settings and records kept by peach-config, which factory-reset and uninstall remove.
+// HARDWARE_CONFIG_FILE describes the device rather than choices made on it, so only uninstall removes it.
+pub const STATE_FILES: [&str; 3] = [SETTINGS_FILE, HISTORY_FILE, ROLLBACK_FILE];
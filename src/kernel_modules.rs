@@ -0,0 +1,113 @@
+use log::{info, warn};
+use snafu::ResultExt;
+use std::fs;
+use std::path::Path;
+
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+use crate::utils::cmd;
+
+// Drop-in file listing the kernel modules which peach-config loads at boot time
+pub const MODULES_LOAD_FILE: &str = "/etc/modules-load.d/peachcloud.conf";
+
+// File which earlier versions of peach-config overwrote with the modules they required
+pub const ETC_MODULES: &str = "/etc/modules";
+
+// Modules which earlier versions of peach-config wrote to /etc/modules,
+// these are migrated to the drop-in file when it is first written
+const LEGACY_MODULES: [&str; 3] = ["i2c-dev", "i2c-bcm2835", "rtc-ds1307"];
+
+/// Returns the list of kernel modules in the peach-config drop-in file
+pub fn read_managed_modules() -> Result<Vec<String>, PeachConfigError> {
+    if !Path::new(MODULES_LOAD_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(MODULES_LOAD_FILE).context(FileReadError {
+        file: MODULES_LOAD_FILE.to_string(),
+    })?;
+    Ok(parse_modules(&contents))
+}
+
+/// Parses a modules-load.d style file, ignoring blank lines and comments
+fn parse_modules(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with(';'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Sets the kernel modules which peach-config loads at boot time.
+///
+/// Only the peach-config drop-in file is rewritten, modules added to /etc/modules or
+/// other drop-ins by the user or other packages are left untouched. The drop-in file is
+/// kept even if no modules are required, as it records that the modules which earlier
+/// versions of peach-config wrote to /etc/modules have been migrated.
+///
+/// # Arguments
+///
+/// * `modules` - the complete list of modules which peach-config requires
+/// * `load_now` - a bool, if true, newly required modules are loaded immediately with modprobe
+///   and modules which are no longer required are unloaded
+pub fn set_managed_modules(modules: &[&str], load_now: bool) -> Result<(), PeachConfigError> {
+    let current = read_managed_modules()?;
+    // legacy modules are only migrated before the drop-in file is first written, so that
+    // the same modules added to /etc/modules by the user later on are left in place
+    if !Path::new(MODULES_LOAD_FILE).exists() {
+        remove_legacy_modules()?;
+    }
+
+    let mut contents =
+        "# Kernel modules required by PeachCloud, managed by peach-config\n".to_string();
+    for module in modules {
+        contents.push_str(module);
+        contents.push('\n');
+    }
+    cmd(&["mkdir", "-p", "/etc/modules-load.d"])?;
+    fs::write(MODULES_LOAD_FILE, contents).context(FileWriteError {
+        file: MODULES_LOAD_FILE.to_string(),
+    })?;
+
+    if load_now {
+        for module in modules.iter().filter(|m| !current.iter().any(|c| c == *m)) {
+            if let Err(err) = cmd(&["modprobe", module]) {
+                warn!("failed to load kernel module {}: {}", module, err);
+            }
+        }
+        // unload in reverse order, so that modules are removed before their dependencies
+        for module in current
+            .iter()
+            .rev()
+            .filter(|c| !modules.contains(&c.as_str()))
+        {
+            if let Err(err) = cmd(&["modprobe", "-r", module]) {
+                warn!("failed to unload kernel module {}: {}", module, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the modules which earlier versions of peach-config wrote to /etc/modules,
+/// leaving any other entries in place
+fn remove_legacy_modules() -> Result<(), PeachConfigError> {
+    if !Path::new(ETC_MODULES).exists() {
+        return Ok(());
+    }
+    let contents = fs::read_to_string(ETC_MODULES).context(FileReadError {
+        file: ETC_MODULES.to_string(),
+    })?;
+    let lines: Vec<&str> = contents
+        .lines()
+        .filter(|line| !LEGACY_MODULES.contains(&line.trim()))
+        .collect();
+    if lines.len() != contents.lines().count() {
+        info!("migrating PeachCloud kernel modules out of {}", ETC_MODULES);
+        let mut new_contents = lines.join("\n");
+        new_contents.push('\n');
+        fs::write(ETC_MODULES, new_contents).context(FileWriteError {
+            file: ETC_MODULES.to_string(),
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use log::info;
+use snafu::ResultExt;
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::process::parent_id;
+use std::process;
+
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+
+// Directory of runtime files, which is emptied on boot
+pub const RUN_DIR: &str = "/run/peachcloud";
+
+// Lock file which a peach-config process changing the system holds an flock on,
+// containing its pid on the first line and its command line on the second
+pub const LOCK_FILE: &str = "/run/peachcloud/peach-config.lock";
+
+// Environment variable through which a process holding the lock passes it on to a
+// peach-config it runs, containing the pid of the process holding the lock
+pub const LOCK_PID_ENV: &str = "PEACH_CONFIG_LOCK_PID";
+
+/// The global peach-config lock, which is released when this is dropped
+#[derive(Debug)]
+pub struct Lock {
+    // the locked file, or None if the lock is held by the parent process
+    file: Option<File>,
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // the flock is released when the file is closed, the pid and command line
+        // are cleared so that they do not name a process which has finished
+        if let Some(file) = &self.file {
+            let _ = file.set_len(0);
+        }
+    }
+}
+
+/// Reads the pid and command line of the process holding the lock from the lock file,
+/// returning None if they have not been written yet
+fn read_holder(file: &mut File) -> Result<Option<(u32, String)>, PeachConfigError> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_string(&mut contents))
+        .context(FileReadError {
+            file: LOCK_FILE.to_string(),
+        })?;
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|pid| pid.trim().parse().ok());
+    Ok(pid.map(|pid| (pid, lines.next().unwrap_or_default().to_string())))
+}
+
+/// Returns true if the parent of this process holds the lock and passed it on,
+/// i.e. this peach-config was run by a peach-config which holds the lock
+fn is_inherited(file: &mut File) -> Result<bool, PeachConfigError> {
+    let passed_pid: Option<u32> = env::var(LOCK_PID_ENV)
+        .ok()
+        .and_then(|pid| pid.parse().ok());
+    if passed_pid != Some(parent_id()) {
+        return Ok(false);
+    }
+    Ok(read_holder(file)?.is_some_and(|(pid, _)| pid == parent_id()))
+}
+
+/// Takes the global lock which prevents peach-config processes from changing the system
+/// at the same time.
+///
+/// The lock is an flock on the lock file, which the kernel releases when the process holding it
+/// exits, so a process which was killed never leaves a stale lock behind. The pid and command
+/// line in the lock file are only used to name the holder. A peach-config run by the process
+/// holding the lock, with the pid of that process in PEACH_CONFIG_LOCK_PID, shares its lock.
+///
+/// # Arguments
+///
+/// * `wait` - a bool, if true blocks until the lock is free instead of returning
+///   a LockError naming the process which holds it
+pub fn acquire_lock(wait: bool) -> Result<Lock, PeachConfigError> {
+    fs::create_dir_all(RUN_DIR).context(FileWriteError {
+        file: RUN_DIR.to_string(),
+    })?;
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(LOCK_FILE)
+        .context(FileWriteError {
+            file: LOCK_FILE.to_string(),
+        })?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            if is_inherited(&mut file)? {
+                return Ok(Lock { file: None });
+            }
+            let (pid, holder) = read_holder(&mut file)?.unwrap_or_default();
+            if !wait {
+                return Err(PeachConfigError::LockError {
+                    pid,
+                    command: holder,
+                });
+            }
+            info!("waiting for {} (pid {}) to finish", holder, pid);
+            file.lock().context(FileWriteError {
+                file: LOCK_FILE.to_string(),
+            })?;
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(err).context(FileWriteError {
+                file: LOCK_FILE.to_string(),
+            })
+        }
+    }
+    let command: Vec<String> = env::args().collect();
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| writeln!(file, "{}\n{}", process::id(), command.join(" ")))
+        .context(FileWriteError {
+            file: LOCK_FILE.to_string(),
+        })?;
+    Ok(Lock { file: Some(file) })
+}
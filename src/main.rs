@@ -1,20 +1,60 @@
+mod apt;
+mod auto_update;
+mod board;
+mod bundle;
+mod config_txt;
 mod constants;
+mod debian_version;
+mod dpkg;
 mod error;
 mod generate_manifest;
+mod health;
+mod history;
+mod i2c;
+mod inventory;
+mod kernel_modules;
+mod lock;
+mod manifest_diff;
+mod os_release;
+mod pinning;
+mod probe_hardware;
+mod requirements;
+mod rollback;
+mod rtc;
+mod settings;
+mod setup_hardware;
 mod setup_networking;
 mod setup_peach;
 mod setup_peach_deb;
+mod uninstall;
 mod update;
 mod utils;
 
 use clap::arg_enum;
 use log::error;
 use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 use structopt::StructOpt;
 
-use crate::generate_manifest::generate_manifest;
+use crate::auto_update::{
+    auto_update_status, disable_auto_update, enable_auto_update, run_auto_update, MaintenanceWindow,
+};
+use crate::board::Board;
+use crate::bundle::create_bundle;
+use crate::generate_manifest::{generate_manifest, print_manifest_schema};
+use crate::health::health;
+use crate::history::print_history;
+use crate::lock::acquire_lock;
+use crate::manifest_diff::manifest_diff;
+use crate::pinning::ServiceSpec;
+use crate::requirements::Profile;
+use crate::rtc::{rtc_status, rtc_sync};
+use crate::settings::Channel;
+use crate::setup_hardware::set_hardware;
 use crate::setup_peach::setup_peach;
-use crate::update::update;
+use crate::setup_peach_deb::{set_apt, set_channel};
+use crate::uninstall::{factory_reset, uninstall};
+use crate::update::{update, ResumePoint};
 
 #[derive(StructOpt, Debug)]
 #[structopt(
@@ -22,9 +62,14 @@ use crate::update::update;
     about = "a CLI tool for updating, installing and configuring PeachCloud"
 )]
 struct Opt {
+    /// Log info messages, unless RUST_LOG selects another level
     #[structopt(short, long)]
     verbose: bool,
 
+    /// Wait for another peach-config which is changing the system to finish, instead of failing
+    #[structopt(long)]
+    wait: bool,
+
     // SUBCOMMANDS
     #[structopt(subcommand)]
     commands: Option<PeachConfig>,
@@ -35,7 +80,7 @@ struct Opt {
 enum PeachConfig {
     /// Prints json manifest of peach configurations
     #[structopt(name = "manifest")]
-    Manifest,
+    Manifest(ManifestOpts),
 
     /// Idempotent setup of PeachCloud
     #[structopt(name = "setup")]
@@ -44,6 +89,92 @@ enum PeachConfig {
     /// Updates all PeachCloud microservices
     #[structopt(name = "update")]
     Update(UpdateOpts),
+
+    /// Reads and sets the hardware real-time clock
+    #[structopt(name = "rtc")]
+    Rtc(RtcCommand),
+
+    /// Changes hardware configurations after setup
+    #[structopt(name = "hardware")]
+    Hardware(HardwareCommand),
+
+    /// Checks that the PeachCloud microservices are running
+    #[structopt(name = "health")]
+    Health(HealthOpts),
+
+    /// Selects the release channel which PeachCloud packages are installed from
+    #[structopt(name = "channel")]
+    Channel(ChannelCommand),
+
+    /// Creates offline bundles of packages
+    #[structopt(name = "bundle")]
+    Bundle(BundleCommand),
+
+    /// Configures the proxy and repository mirror apt uses
+    #[structopt(name = "apt")]
+    Apt(AptCommand),
+
+    /// Applies updates automatically during a maintenance window
+    #[structopt(name = "auto-update")]
+    AutoUpdate(AutoUpdateCommand),
+
+    /// Prints the log of updates, setups and rollbacks
+    #[structopt(name = "history")]
+    History(HistoryOpts),
+
+    /// Removes PeachCloud from the device, keeping the operating system
+    #[structopt(name = "uninstall")]
+    Uninstall(ConfirmOpts),
+
+    /// Restores the PeachCloud configuration of the device to its defaults
+    #[structopt(name = "factory-reset")]
+    FactoryReset(ConfirmOpts),
+}
+
+impl PeachConfig {
+    /// Returns true if the subcommand changes the system, and so must hold the peach-config lock
+    fn needs_lock(&self) -> bool {
+        match self {
+            PeachConfig::Setup(_)
+            | PeachConfig::Hardware(_)
+            | PeachConfig::Channel(_)
+            | PeachConfig::Apt(_)
+            | PeachConfig::Uninstall(_)
+            | PeachConfig::FactoryReset(_)
+            | PeachConfig::Rtc(RtcCommand::Sync(_)) => true,
+            PeachConfig::Update(opts) => !opts.only_lists(),
+            PeachConfig::AutoUpdate(command) => !matches!(command, AutoUpdateCommand::Status),
+            _ => false,
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct ManifestOpts {
+    /// Print the JSON Schema of the manifest instead of the manifest
+    #[structopt(long)]
+    schema: bool,
+
+    #[structopt(subcommand)]
+    command: Option<ManifestCommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum ManifestCommand {
+    /// Compares two manifests, or a manifest with the running system
+    #[structopt(name = "diff")]
+    Diff(ManifestDiffOpts),
+}
+
+#[derive(StructOpt, Debug)]
+struct ManifestDiffOpts {
+    /// Path of the manifest to compare from
+    a: String,
+    /// Path of the manifest to compare to, defaults to the running system
+    b: Option<String>,
+    /// Print the differences as json
+    #[structopt(short, long)]
+    json: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -52,7 +183,7 @@ struct SetupOpts {
     #[structopt(short, long)]
     i2c: bool,
     /// Optionally select which model of real-time-clock is being used,
-    /// {ds1307, ds3231}
+    /// {ds1307, ds3231, pcf8523, pcf85063, mcp7940n, auto}
     #[structopt(short, long)]
     rtc: Option<RtcOption>,
     /// Run peach-config in non-interactive mode
@@ -61,6 +192,14 @@ struct SetupOpts {
     /// Use the default en_US.UTF-8 locale for compatability
     #[structopt(short, long)]
     default_locale: bool,
+    /// Override the detected hardware board,
+    /// {pi3, pi4, pizero2, genericarm64, genericamd64}
+    #[structopt(short, long)]
+    board: Option<Board>,
+    /// Set of system packages to install, {minimal, developer}. The developer profile
+    /// adds tools such as git, vim and build-essential
+    #[structopt(short, long, default_value = "minimal")]
+    profile: Profile,
 }
 
 #[derive(StructOpt, Debug)]
@@ -74,39 +213,317 @@ pub struct UpdateOpts {
     /// List microservices which are available for updating
     #[structopt(short, long)]
     list: bool,
+    /// Only update the given service, optionally to a version e.g. peach-web=1.2.3,
+    /// can be passed multiple times
+    #[structopt(long = "service", number_of_values = 1)]
+    services: Vec<ServiceSpec>,
+    /// Hold a service at a version e.g. peach-web=1.2.3, or at its installed version
+    /// if no version is given, can be passed multiple times
+    #[structopt(long, number_of_values = 1)]
+    pin: Vec<ServiceSpec>,
+    /// Release the version hold of a service, can be passed multiple times
+    #[structopt(long, number_of_values = 1)]
+    unpin: Vec<ServiceSpec>,
+    /// Reinstall the previous versions if the update or a health check fails
+    #[structopt(long)]
+    auto_rollback: bool,
+    /// Resume token passed by a full update to the freshly installed peach-config
+    #[structopt(long, hidden = true)]
+    resume: Option<ResumePoint>,
+    /// Install the packages of an offline bundle, a directory or tarball, instead of using apt
+    #[structopt(long)]
+    from: Option<String>,
+    /// Keyring which the offline bundle must be signed by, by default the PeachCloud apt keyring
+    #[structopt(long, requires = "from")]
+    keyring: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<UpdateCommand>,
+}
+
+impl UpdateOpts {
+    /// Returns true if the update only lists the available updates, which is the case if --list
+    /// is passed without any of the options which update takes in preference to it
+    fn only_lists(&self) -> bool {
+        self.list
+            && self.command.is_none()
+            && self.pin.is_empty()
+            && self.unpin.is_empty()
+            && self.from.is_none()
+            && self.services.is_empty()
+            && !self.self_only
+            && !self.microservices
+            && self.resume.is_none()
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum UpdateCommand {
+    /// Reinstalls the versions of the microservices which were installed before the last update
+    #[structopt(name = "rollback")]
+    Rollback,
+}
+
+#[derive(StructOpt, Debug)]
+struct HealthOpts {
+    /// Print the report as json instead of a table
+    #[structopt(short, long)]
+    json: bool,
+}
+
+#[derive(StructOpt, Debug)]
+enum BundleCommand {
+    /// Creates a signed offline bundle of .deb files
+    #[structopt(name = "create")]
+    Create(BundleCreateOpts),
+}
+
+#[derive(StructOpt, Debug)]
+struct BundleCreateOpts {
+    /// Directory or tarball (.tar, .tar.gz, .tgz or .tar.xz) to create the bundle at
+    #[structopt(short, long)]
+    output: String,
+    /// Package to include as name[=version], can be passed multiple times,
+    /// by default the PeachCloud services installed on this device are included
+    #[structopt(long, number_of_values = 1)]
+    package: Vec<String>,
+    /// Id of the gpg key to sign the bundle with, by default the default key of gpg.
+    /// Unless this is the PeachCloud repository key, install the bundle with --keyring
+    #[structopt(long)]
+    sign_with: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct HistoryOpts {
+    /// Print the entries as json instead of a table
+    #[structopt(short, long)]
+    json: bool,
+}
+
+#[derive(StructOpt, Debug)]
+struct ConfirmOpts {
+    /// Run peach-config in non-interactive mode, without asking for confirmation
+    #[structopt(short, long)]
+    no_input: bool,
+}
+
+#[derive(StructOpt, Debug)]
+enum AutoUpdateCommand {
+    /// Installs and starts the auto-update timer
+    #[structopt(name = "enable")]
+    Enable(AutoUpdateEnableOpts),
+    /// Stops and removes the auto-update timer
+    #[structopt(name = "disable")]
+    Disable,
+    /// Shows whether automatic updates are enabled and the result of the last one
+    #[structopt(name = "status")]
+    Status,
+    /// Applies available updates if inside the maintenance window, run by the auto-update timer
+    #[structopt(name = "run")]
+    Run,
+}
+
+#[derive(StructOpt, Debug)]
+struct AutoUpdateEnableOpts {
+    /// Daily window of local time in which updates are applied, as HH:MM-HH:MM,
+    /// by default the saved window or 03:00-05:00
+    #[structopt(long)]
+    window: Option<MaintenanceWindow>,
+}
+
+#[derive(StructOpt, Debug)]
+enum AptCommand {
+    /// Sets the proxy apt downloads through and the mirror of the PeachCloud repository
+    #[structopt(name = "set")]
+    Set(AptSetOpts),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct AptSetOpts {
+    /// Url of an http proxy such as apt-cacher-ng e.g. http://10.0.0.1:3142, or none to remove it
+    #[structopt(long)]
+    proxy: Option<AptUrl>,
+    /// Url of a mirror of the PeachCloud apt repository, or default to use apt.peachcloud.org
+    #[structopt(long)]
+    repo: Option<AptUrl>,
+    /// Seconds to wait for another package manager to release the dpkg lock, 600 by default
+    #[structopt(long)]
+    lock_timeout: Option<u64>,
+}
+
+/// Url setting which can also be reset, with "none" or "default"
+#[derive(Debug)]
+pub enum AptUrl {
+    Default,
+    Url(String),
+}
+
+impl FromStr for AptUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") || s.eq_ignore_ascii_case("default") {
+            Ok(AptUrl::Default)
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(AptUrl::Url(s.to_string()))
+        } else {
+            Err(format!("expected an http:// or https:// url, found {}", s))
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum ChannelCommand {
+    /// Switches to the given release channel
+    #[structopt(name = "set")]
+    Set(ChannelSetOpts),
+}
+
+#[derive(StructOpt, Debug)]
+struct ChannelSetOpts {
+    /// Release channel, {stable, testing, nightly}
+    channel: Channel,
+}
+
+#[derive(StructOpt, Debug)]
+enum RtcCommand {
+    /// Shows the time of the hardware clock and the system time
+    #[structopt(name = "status")]
+    Status,
+    /// Sets the hardware clock from the system time
+    #[structopt(name = "sync")]
+    Sync(RtcSyncOpts),
+}
+
+#[derive(StructOpt, Debug)]
+struct RtcSyncOpts {
+    /// Set the system time from the hardware clock instead
+    #[structopt(short, long)]
+    to_system: bool,
+}
+
+#[derive(StructOpt, Debug)]
+enum HardwareCommand {
+    /// Enables or disables i2c and real-time clock configurations
+    #[structopt(name = "set")]
+    Set(HardwareSetOpts),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct HardwareSetOpts {
+    /// Turn i2c configurations on or off, {on, off}
+    #[structopt(long)]
+    i2c: Option<Switch>,
+    /// Select which model of real-time-clock is being used, or none to remove it,
+    /// {none, ds1307, ds3231, pcf8523, pcf85063, mcp7940n, auto}
+    #[structopt(long)]
+    rtc: Option<RtcChoice>,
 }
 
 arg_enum! {
-    /// enum options for real-time clock choices
+    /// enum options for turning a configuration on or off
     #[derive(Debug)]
+    pub enum Switch {
+        On,
+        Off
+    }
+}
+
+/// Real-time clock option which can also remove a configured clock
+#[derive(Debug)]
+pub enum RtcChoice {
+    None,
+    Model(RtcOption),
+}
+
+impl FromStr for RtcChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") {
+            Ok(RtcChoice::None)
+        } else {
+            s.parse().map(RtcChoice::Model)
+        }
+    }
+}
+
+arg_enum! {
+    /// enum options for real-time clock choices,
+    /// Auto probes the i2c bus and is resolved to one of the other models
+    #[derive(Debug, Clone, PartialEq, Eq)]
     #[allow(non_camel_case_types)]
     #[allow(clippy::enum_variant_names)]
     #[derive(Serialize, Deserialize)]
     pub enum RtcOption {
         DS1307,
-        DS3231
+        DS3231,
+        PCF8523,
+        PCF85063,
+        MCP7940N,
+        Auto
     }
 }
 
 fn main() {
-    // initialize the logger
-    env_logger::init();
-
     // parse cli arguments
     let opt = Opt::from_args();
 
+    // initialize the logger, logging info messages by default if verbose is set
+    let mut logger = env_logger::Builder::new();
+    if opt.verbose {
+        logger.filter_level(log::LevelFilter::Info);
+    }
+    // directives from RUST_LOG are parsed last, so that they take precedence
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    logger.init();
+
     // switch based on subcommand
     if let Some(subcommand) = opt.commands {
+        // held until the subcommand has finished
+        let _lock = if subcommand.needs_lock() {
+            match acquire_lock(opt.wait) {
+                Ok(lock) => Some(lock),
+                Err(err) => {
+                    error!("{}", err);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
         match subcommand {
             PeachConfig::Setup(cfg) => {
-                match setup_peach(cfg.no_input, cfg.default_locale, cfg.i2c, cfg.rtc) {
+                match setup_peach(
+                    cfg.no_input,
+                    cfg.default_locale,
+                    cfg.i2c,
+                    cfg.rtc,
+                    cfg.board,
+                    cfg.profile,
+                ) {
                     Ok(_) => {}
                     Err(err) => {
                         error!("peach-config encountered an error: {}", err)
                     }
                 }
             }
-            PeachConfig::Manifest => match generate_manifest() {
+            PeachConfig::Manifest(ManifestOpts {
+                command: Some(ManifestCommand::Diff(opts)),
+                ..
+            }) => match manifest_diff(&opts.a, opts.b.as_deref(), opts.json) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "peach-config encountered an error comparing manifests: {}",
+                        err
+                    )
+                }
+            },
+            PeachConfig::Manifest(opts) if opts.schema => print_manifest_schema(),
+            PeachConfig::Manifest(_) => match generate_manifest() {
                 Ok(_) => {}
                 Err(err) => {
                     error!(
@@ -121,6 +538,103 @@ fn main() {
                     error!("peach-config encountered an error during update: {}", err)
                 }
             },
+            PeachConfig::Rtc(rtc_command) => {
+                let result = match rtc_command {
+                    RtcCommand::Status => rtc_status(),
+                    RtcCommand::Sync(opts) => rtc_sync(opts.to_system),
+                };
+                match result {
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!("peach-config encountered an error with the rtc: {}", err)
+                    }
+                }
+            }
+            PeachConfig::Hardware(HardwareCommand::Set(opts)) => match set_hardware(opts) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "peach-config encountered an error changing hardware configurations: {}",
+                        err
+                    )
+                }
+            },
+            PeachConfig::Health(opts) => match health(opts.json) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!("peach-config encountered an error checking health: {}", err)
+                }
+            },
+            PeachConfig::Channel(ChannelCommand::Set(opts)) => match set_channel(opts.channel) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "peach-config encountered an error setting the channel: {}",
+                        err
+                    )
+                }
+            },
+            PeachConfig::Bundle(BundleCommand::Create(opts)) => {
+                match create_bundle(&opts.output, &opts.package, opts.sign_with.as_deref()) {
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!(
+                            "peach-config encountered an error creating a bundle: {}",
+                            err
+                        )
+                    }
+                }
+            }
+            PeachConfig::Apt(AptCommand::Set(opts)) => match set_apt(opts) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!("peach-config encountered an error configuring apt: {}", err)
+                }
+            },
+            PeachConfig::AutoUpdate(command) => {
+                let result = match command {
+                    AutoUpdateCommand::Enable(opts) => enable_auto_update(opts.window),
+                    AutoUpdateCommand::Disable => disable_auto_update(),
+                    AutoUpdateCommand::Status => auto_update_status(),
+                    AutoUpdateCommand::Run => run_auto_update(),
+                };
+                match result {
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!(
+                            "peach-config encountered an error with auto-update: {}",
+                            err
+                        )
+                    }
+                }
+            }
+            PeachConfig::History(opts) => match print_history(opts.json) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "peach-config encountered an error reading the history: {}",
+                        err
+                    )
+                }
+            },
+            PeachConfig::Uninstall(opts) => match uninstall(opts.no_input) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "peach-config encountered an error during uninstall: {}",
+                        err
+                    )
+                }
+            },
+            PeachConfig::FactoryReset(opts) => match factory_reset(opts.no_input) {
+                Ok(_) => {}
+                Err(err) => {
+                    error!(
+                        "peach-config encountered an error during factory reset: {}",
+                        err
+                    )
+                }
+            },
         }
     }
 }
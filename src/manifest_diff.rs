@@ -0,0 +1,356 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use crate::error::PeachConfigError;
+use crate::generate_manifest::{build_manifest, load_manifest, Manifest};
+
+// Top level fields of the manifest which describe when it was generated rather than the device,
+// and so are expected to differ between any two manifests
+const IGNORED_FIELDS: [&str; 2] = ["schema_version", "generated_at"];
+
+// Fields which identify an element of an array of objects, checked in order, so that e.g. the
+// filesystems are compared by mount point rather than by the order in which df listed them
+const ID_FIELDS: [&str; 4] = ["mountpoint", "name", "phy", "bus"];
+
+/// A single difference between two manifests
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum Change {
+    Added { key: String, value: Value },
+    Removed { key: String, value: Value },
+    Changed { key: String, from: Value, to: Value },
+}
+
+/// Output form of manifest diff, with the differences grouped by section
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestDiff {
+    // changes to the installed package versions, keyed by package name
+    packages: Vec<Change>,
+    // changes to the hardware configuration, keyed by field name
+    hardware: Vec<Change>,
+    // changes to the probed hardware, keyed by field name
+    probed_hardware: Vec<Change>,
+    // changes to every other field of the manifest e.g. hostname or os_release.version_id
+    settings: Vec<Change>,
+}
+
+impl ManifestDiff {
+    fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+            && self.hardware.is_empty()
+            && self.probed_hardware.is_empty()
+            && self.settings.is_empty()
+    }
+}
+
+/// Compares two manifests and prints their differences
+///
+/// # Arguments
+///
+/// * `a` - path to the manifest which is compared from
+/// * `b` - optional path to the manifest which is compared to,
+///   if not supplied the manifest of the running system is used
+/// * `json` - a bool, if true the differences are printed as json
+pub fn manifest_diff(a: &str, b: Option<&str>, json: bool) -> Result<(), PeachConfigError> {
+    let a_manifest = load_manifest(a)?;
+    let b_manifest = match b {
+        Some(path) => load_manifest(path)?,
+        None => build_manifest()?,
+    };
+    let diff = diff_manifests(&a_manifest, &b_manifest)?;
+    if json {
+        println!("{}", serde_json::to_string(&diff)?);
+    } else {
+        print_diff(&diff, a, b.unwrap_or("live system"));
+    }
+    Ok(())
+}
+
+/// Computes the differences between two manifests
+pub fn diff_manifests(a: &Manifest, b: &Manifest) -> Result<ManifestDiff, PeachConfigError> {
+    let mut a = into_object(serde_json::to_value(a)?);
+    let mut b = into_object(serde_json::to_value(b)?);
+    for field in &IGNORED_FIELDS {
+        a.remove(*field);
+        b.remove(*field);
+    }
+
+    // packages are compared by name, every other section is flattened into dotted keys
+    let packages = diff_maps(
+        &into_sorted(a.remove("packages")),
+        &into_sorted(b.remove("packages")),
+    );
+    let mut section = |name: &str| {
+        let (mut a_flat, mut b_flat) = (BTreeMap::new(), BTreeMap::new());
+        flatten("", a.remove(name).unwrap_or(Value::Null), &mut a_flat);
+        flatten("", b.remove(name).unwrap_or(Value::Null), &mut b_flat);
+        diff_maps(&a_flat, &b_flat)
+    };
+    let hardware = section("hardware");
+    let probed_hardware = section("probed_hardware");
+
+    let (mut a_flat, mut b_flat) = (BTreeMap::new(), BTreeMap::new());
+    flatten("", Value::Object(a), &mut a_flat);
+    flatten("", Value::Object(b), &mut b_flat);
+    let settings = diff_maps(&a_flat, &b_flat);
+
+    Ok(ManifestDiff {
+        packages,
+        hardware,
+        probed_hardware,
+        settings,
+    })
+}
+
+fn into_object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+fn into_sorted(value: Option<Value>) -> BTreeMap<String, Value> {
+    into_object(value.unwrap_or(Value::Null))
+        .into_iter()
+        .collect()
+}
+
+/// Returns the key of an element of an array: the value of its identifying field if it is an
+/// object with one of ID_FIELDS, the value itself if it is a string or number, and otherwise
+/// its index
+fn element_key(index: usize, value: &Value) -> String {
+    match value {
+        Value::Object(map) => ID_FIELDS
+            .iter()
+            .find_map(|field| map.get(*field))
+            .map(display_value)
+            .unwrap_or_else(|| index.to_string()),
+        Value::String(_) | Value::Number(_) => display_value(value),
+        _ => index.to_string(),
+    }
+}
+
+/// Flattens nested objects and arrays into a map of keys such as "os_release.version_id"
+/// or "filesystems[/boot].available_bytes", where the elements of arrays are keyed by
+/// element_key. Null values are treated as absent.
+fn flatten(prefix: &str, value: Value, flat: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, flat);
+            }
+        }
+        Value::Array(values) => {
+            for (index, value) in values.into_iter().enumerate() {
+                let key = format!("{}[{}]", prefix, element_key(index, &value));
+                flatten(&key, value, flat);
+            }
+        }
+        Value::Null => {}
+        value => {
+            flat.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+/// Returns the entries which were added, removed or changed between two maps
+fn diff_maps(a: &BTreeMap<String, Value>, b: &BTreeMap<String, Value>) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (key, a_value) in a {
+        match b.get(key) {
+            None => changes.push(Change::Removed {
+                key: key.clone(),
+                value: a_value.clone(),
+            }),
+            Some(b_value) if b_value != a_value => changes.push(Change::Changed {
+                key: key.clone(),
+                from: a_value.clone(),
+                to: b_value.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (key, b_value) in b {
+        if !a.contains_key(key) {
+            changes.push(Change::Added {
+                key: key.clone(),
+                value: b_value.clone(),
+            });
+        }
+    }
+    changes
+}
+
+/// Formats a json value for human-readable output, without quotes around strings
+fn display_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Prints the differences in a form similar to a unified diff,
+/// with added entries prefixed by +, removed entries by - and changed entries by ~
+fn print_diff(diff: &ManifestDiff, a: &str, b: &str) {
+    println!("--- {}", a);
+    println!("+++ {}", b);
+    if diff.is_empty() {
+        println!("manifests are identical");
+        return;
+    }
+    let sections = [
+        ("packages", &diff.packages),
+        ("hardware", &diff.hardware),
+        ("probed_hardware", &diff.probed_hardware),
+        ("settings", &diff.settings),
+    ];
+    for (name, changes) in sections.iter() {
+        if changes.is_empty() {
+            continue;
+        }
+        println!("{}:", name);
+        for change in changes.iter() {
+            match change {
+                Change::Added { key, value } => println!("  + {}: {}", key, display_value(value)),
+                Change::Removed { key, value } => {
+                    println!("  - {}: {}", key, display_value(value))
+                }
+                Change::Changed { key, from, to } => println!(
+                    "  ~ {}: {} -> {}",
+                    key,
+                    display_value(from),
+                    display_value(to)
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filesystem(mountpoint: &str, available_bytes: u64) -> Value {
+        json!({
+            "source": format!("/dev/mmcblk0p{}", mountpoint.len()),
+            "mountpoint": mountpoint,
+            "size_bytes": 1000,
+            "available_bytes": available_bytes,
+        })
+    }
+
+    fn probed_hardware(filesystems: Value, gpio_chips: Value) -> Value {
+        json!({
+            "board_model": null,
+            "board": null,
+            "ram_total_kb": null,
+            "storage_devices": [],
+            "filesystems": filesystems,
+            "i2c_buses": [],
+            "oled_present": false,
+            "rtc_present": false,
+            "gpio_chips": gpio_chips,
+            "wifi_adapters": [],
+        })
+    }
+
+    fn manifest(changes: Value) -> Manifest {
+        let mut manifest = json!({
+            "schema_version": 1,
+            "generated_at": "2021-01-01T00:00:00Z",
+            "hostname": "peach",
+            "os_release": { "id": "debian", "version_id": "10" },
+            "packages": { "peach-web": "0.6.0", "peach-oled": "0.2.0" },
+            "hardware": { "i2c": true, "rtc": "DS3231", "board": "Pi3" },
+            "probed_hardware": probed_hardware(
+                json!([filesystem("/", 500), filesystem("/boot", 100)]),
+                json!(["gpiochip0"]),
+            ),
+        });
+        for (key, value) in into_object(changes) {
+            manifest[key] = value;
+        }
+        serde_json::from_value(manifest).unwrap()
+    }
+
+    fn keys(changes: &[Change]) -> Vec<String> {
+        changes
+            .iter()
+            .map(|change| match change {
+                Change::Added { key, .. } => format!("+{}", key),
+                Change::Removed { key, .. } => format!("-{}", key),
+                Change::Changed { key, .. } => format!("~{}", key),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical_manifests_have_no_changes() {
+        let diff = diff_manifests(&manifest(json!({})), &manifest(json!({}))).unwrap();
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn schema_version_and_generated_at_are_ignored() {
+        let b = manifest(json!({ "schema_version": 2, "generated_at": "2022-06-01T12:00:00Z" }));
+        let diff = diff_manifests(&manifest(json!({})), &b).unwrap();
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn packages_are_added_removed_and_changed() {
+        let b = manifest(json!({
+            "packages": { "peach-web": "0.6.1", "peach-stats": "0.1.0" }
+        }));
+        let diff = diff_manifests(&manifest(json!({})), &b).unwrap();
+        assert_eq!(
+            keys(&diff.packages),
+            ["-peach-oled", "~peach-web", "+peach-stats"]
+        );
+        match &diff.packages[1] {
+            Change::Changed { from, to, .. } => {
+                assert_eq!(from, "0.6.0");
+                assert_eq!(to, "0.6.1");
+            }
+            change => panic!("unexpected change {:?}", change),
+        }
+        assert!(diff.hardware.is_empty());
+        assert!(diff.settings.is_empty());
+    }
+
+    #[test]
+    fn nested_objects_are_flattened() {
+        let b = manifest(json!({
+            "os_release": { "id": "debian", "version_codename": "bookworm" },
+            "hardware": { "i2c": false, "board": "Pi3" },
+        }));
+        let diff = diff_manifests(&manifest(json!({})), &b).unwrap();
+        assert_eq!(keys(&diff.hardware), ["~i2c", "-rtc"]);
+        assert_eq!(
+            keys(&diff.settings),
+            ["-os_release.version_id", "+os_release.version_codename"]
+        );
+    }
+
+    #[test]
+    fn array_elements_are_compared_by_their_identifying_field() {
+        let b = manifest(json!({
+            "probed_hardware": probed_hardware(
+                json!([filesystem("/boot", 100), filesystem("/", 400)]),
+                json!(["gpiochip1", "gpiochip0"]),
+            )
+        }));
+        let diff = diff_manifests(&manifest(json!({})), &b).unwrap();
+        assert_eq!(
+            keys(&diff.probed_hardware),
+            ["~filesystems[/].available_bytes", "+gpio_chips[gpiochip1]"]
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+// File which identifies the operating system, see os-release(5)
+pub const OS_RELEASE_FILE: &str = "/etc/os-release";
+
+/// The fields of /etc/os-release which are relevant to PeachCloud
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OsRelease {
+    // e.g. "debian"
+    pub id: Option<String>,
+    // e.g. "10"
+    pub version_id: Option<String>,
+    // e.g. "buster"
+    pub version_codename: Option<String>,
+    // e.g. "Debian GNU/Linux 10 (buster)"
+    pub pretty_name: Option<String>,
+}
+
+/// Parses the KEY=value lines of an os-release file, removing any quotes around values
+fn parse_os_release(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim().trim_matches('"').trim_matches('\'');
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+/// Reads /etc/os-release, returning None if it does not exist
+pub fn read_os_release() -> Option<OsRelease> {
+    let contents = fs::read_to_string(OS_RELEASE_FILE).ok()?;
+    let mut fields = parse_os_release(&contents);
+    Some(OsRelease {
+        id: fields.remove("ID"),
+        version_id: fields.remove("VERSION_ID"),
+        version_codename: fields.remove("VERSION_CODENAME"),
+        pretty_name: fields.remove("PRETTY_NAME"),
+    })
+}
@@ -0,0 +1,162 @@
+use log::info;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::apt::PackageSpec;
+use crate::constants::SERVICES;
+use crate::dpkg::parse_control_file;
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+
+// Directory of apt preferences files, in which a file is written for every pinned service
+pub const APT_PREFERENCES_DIR: &str = "/etc/apt/preferences.d";
+
+// Prefix of the apt preferences files written by peach-config
+const PIN_FILE_PREFIX: &str = "peachcloud-";
+
+// Priority above 1000 so that apt installs the pinned version even if it is a downgrade
+const PIN_PRIORITY: &str = "1001";
+
+/// A service name with an optional version, parsed from the form name[=version]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceSpec {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl FromStr for ServiceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, version) = match s.split_once('=') {
+            Some((name, version)) => (name, Some(version.to_string())),
+            None => (s, None),
+        };
+        if !SERVICES.contains(&name) {
+            return Err(format!(
+                "unknown service {}, expected one of: {}",
+                name,
+                SERVICES.join(", ")
+            ));
+        }
+        if version.as_deref() == Some("") {
+            return Err(format!("missing version after {}=", name));
+        }
+        Ok(ServiceSpec {
+            name: name.to_string(),
+            version,
+        })
+    }
+}
+
+impl fmt::Display for ServiceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "{}={}", self.name, version),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl From<&ServiceSpec> for PackageSpec {
+    fn from(spec: &ServiceSpec) -> Self {
+        PackageSpec {
+            name: spec.name.clone(),
+            version: spec.version.clone(),
+        }
+    }
+}
+
+/// Returns the path of the apt preferences file which pins the given service
+fn pin_file(service: &str) -> String {
+    format!("{}/{}{}", APT_PREFERENCES_DIR, PIN_FILE_PREFIX, service)
+}
+
+/// Pins a service at the given version by writing an apt preferences file,
+/// so that apt installs exactly that version until the service is unpinned
+pub fn pin_service(service: &str, version: &str) -> Result<(), PeachConfigError> {
+    info!("pinning {} at version {}", service, version);
+    let path = pin_file(service);
+    let contents = format!(
+        "Explanation: pinned by peach-config, remove with peach-config update --unpin {}\n\
+         Package: {}\n\
+         Pin: version {}\n\
+         Pin-Priority: {}\n",
+        service, service, version, PIN_PRIORITY
+    );
+    fs::create_dir_all(APT_PREFERENCES_DIR).context(FileWriteError {
+        file: APT_PREFERENCES_DIR.to_string(),
+    })?;
+    fs::write(&path, contents).context(FileWriteError { file: path })?;
+    Ok(())
+}
+
+/// Removes the pin of a service, if it was pinned
+pub fn unpin_service(service: &str) -> Result<(), PeachConfigError> {
+    let path = pin_file(service);
+    if Path::new(&path).exists() {
+        info!("unpinning {}", service);
+        fs::remove_file(&path).context(FileWriteError { file: path })?;
+    } else {
+        info!("{} is not pinned", service);
+    }
+    Ok(())
+}
+
+/// Returns a map of pinned services to the version they are pinned at,
+/// read from the apt preferences files written by peach-config
+pub fn get_pinned_services() -> Result<HashMap<String, String>, PeachConfigError> {
+    let mut pinned = HashMap::new();
+    for service in SERVICES.iter() {
+        let path = pin_file(service);
+        if !Path::new(&path).exists() {
+            continue;
+        }
+        let contents = fs::read_to_string(&path).context(FileReadError { file: path })?;
+        let version = parse_control_file(&contents)
+            .into_iter()
+            .filter_map(|mut paragraph| paragraph.remove("Pin"))
+            .find_map(|pin| pin.strip_prefix("version ").map(|v| v.trim().to_string()));
+        if let Some(version) = version {
+            pinned.insert(service.to_string(), version);
+        }
+    }
+    Ok(pinned)
+}
+
+/// Returns the contents of the apt preferences files of the pinned services,
+/// so that the pins can be put back with restore_pins
+pub fn read_pins() -> Result<HashMap<String, String>, PeachConfigError> {
+    let mut pins = HashMap::new();
+    for service in SERVICES.iter() {
+        let path = pin_file(service);
+        if Path::new(&path).exists() {
+            let contents = fs::read_to_string(&path).context(FileReadError { file: path })?;
+            pins.insert(service.to_string(), contents);
+        }
+    }
+    Ok(pins)
+}
+
+/// Puts back the pins returned by read_pins, rewriting the pins which were changed
+/// and removing the pins which were added since
+pub fn restore_pins(pins: &HashMap<String, String>) -> Result<(), PeachConfigError> {
+    for service in SERVICES.iter() {
+        let path = pin_file(service);
+        match pins.get(*service) {
+            Some(contents) => {
+                fs::write(&path, contents).context(FileWriteError { file: path })?;
+            }
+            None => {
+                if Path::new(&path).exists() {
+                    info!("removing the pin of {} written by the update", service);
+                    fs::remove_file(&path).context(FileWriteError { file: path })?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
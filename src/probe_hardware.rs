@@ -0,0 +1,238 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::board::{board_profile, detect_board, read_board_model, Board};
+use crate::i2c::{bus_exists, probe_addresses};
+use crate::rtc::RTC_ADDRESSES;
+use crate::utils::get_output;
+
+// i2c addresses of the SSD1306 OLED display used by peach-oled
+const OLED_ADDRESSES: [u16; 2] = [0x3c, 0x3d];
+
+/// Hardware which was found attached to the device when the manifest was generated,
+/// as opposed to the HardwareConfig, which records what peach-config configured
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProbedHardware {
+    // model name from the device tree or /proc/cpuinfo e.g. "Raspberry Pi 3 Model B Plus Rev 1.3"
+    pub board_model: Option<String>,
+    // hardware profile which matches the board
+    pub board: Option<Board>,
+    // total memory in kilobytes
+    pub ram_total_kb: Option<u64>,
+    pub storage_devices: Vec<StorageDevice>,
+    pub filesystems: Vec<Filesystem>,
+    pub i2c_buses: Vec<I2cBus>,
+    pub oled_present: bool,
+    pub rtc_present: bool,
+    // names of the gpio character devices e.g. "gpiochip0"
+    pub gpio_chips: Vec<String>,
+    pub wifi_adapters: Vec<WifiAdapter>,
+}
+
+/// A disk, as listed by lsblk
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageDevice {
+    pub name: String,
+    pub size_bytes: u64,
+}
+
+/// A mounted filesystem, as listed by df
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Filesystem {
+    pub source: String,
+    pub mountpoint: String,
+    pub size_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// An i2c bus and the addresses of the OLED display and real-time clocks which responded
+/// when it was probed, formatted as hex strings e.g. "0x3c"
+#[derive(Debug, Serialize, Deserialize)]
+pub struct I2cBus {
+    pub bus: u8,
+    pub addresses: Vec<String>,
+}
+
+/// A wireless adapter and whether it can run in access point mode
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WifiAdapter {
+    // name of the wiphy e.g. "phy0"
+    pub phy: String,
+    // network interfaces of the adapter e.g. ["wlan0"]
+    pub interfaces: Vec<String>,
+    pub ap_mode: bool,
+}
+
+/// Probes the hardware attached to the device.
+///
+/// Each probe is independent and a failing probe is logged and reported as empty,
+/// so that the rest of the manifest can still be generated on a device with missing tools.
+pub fn probe_hardware() -> ProbedHardware {
+    let board = detect_board().ok();
+    let i2c_buses = probe_i2c_buses(board);
+    let responds = |addresses: &[u16]| {
+        i2c_buses.iter().any(|bus| {
+            addresses
+                .iter()
+                .any(|address| bus.addresses.contains(&format!("0x{:02x}", address)))
+        })
+    };
+    let oled_present = responds(&OLED_ADDRESSES);
+    let rtc_present = responds(&RTC_ADDRESSES) || !list_dir("/sys/class/rtc", "rtc").is_empty();
+
+    ProbedHardware {
+        board_model: read_board_model(),
+        board,
+        ram_total_kb: probe_ram_total_kb(),
+        storage_devices: probe_storage_devices(),
+        filesystems: probe_filesystems(),
+        oled_present,
+        rtc_present,
+        i2c_buses,
+        gpio_chips: list_dir("/dev", "gpiochip"),
+        wifi_adapters: probe_wifi_adapters(),
+    }
+}
+
+/// Returns the sorted names of the entries of a directory which start with the given prefix,
+/// or an empty list if the directory does not exist
+fn list_dir(dir: &str, prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with(prefix))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+/// Reads MemTotal from /proc/meminfo
+fn probe_ram_total_kb() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+}
+
+/// Lists disks with lsblk
+fn probe_storage_devices() -> Vec<StorageDevice> {
+    let output = match get_output(&["lsblk", "-b", "-d", "-n", "-o", "NAME,SIZE,TYPE"]) {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("failed to list storage devices: {}", err);
+            return Vec::new();
+        }
+    };
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [name, size, "disk"] => Some(StorageDevice {
+                    name: name.to_string(),
+                    size_bytes: size.parse().ok()?,
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Lists mounted filesystems which are backed by a device with df
+fn probe_filesystems() -> Vec<Filesystem> {
+    let output = match get_output(&["df", "-B1", "--output=source,target,size,avail"]) {
+        Ok(output) => output,
+        Err(err) => {
+            warn!("failed to list filesystems: {}", err);
+            return Vec::new();
+        }
+    };
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [source, mountpoint, size, available] if source.starts_with("/dev/") => {
+                    Some(Filesystem {
+                        source: source.to_string(),
+                        mountpoint: mountpoint.to_string(),
+                        size_bytes: size.parse().ok()?,
+                        available_bytes: available.parse().ok()?,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Probes the i2c bus of the board's hardware profile for the OLED display and real-time clocks.
+/// Other buses and addresses are not probed, as devices on them may react to being probed.
+fn probe_i2c_buses(board: Option<Board>) -> Vec<I2cBus> {
+    let bus = match board.and_then(|board| board_profile(board).i2c_bus) {
+        Some(bus) if bus_exists(bus) => bus,
+        _ => return Vec::new(),
+    };
+    let probed: Vec<u16> = OLED_ADDRESSES
+        .iter()
+        .chain(&RTC_ADDRESSES)
+        .copied()
+        .collect();
+    let addresses = match probe_addresses(bus, &probed) {
+        Ok(addresses) => addresses
+            .iter()
+            .map(|address| format!("0x{:02x}", address))
+            .collect(),
+        Err(err) => {
+            warn!("failed to probe i2c bus {}: {}", bus, err);
+            Vec::new()
+        }
+    };
+    vec![I2cBus { bus, addresses }]
+}
+
+/// Lists wireless adapters from /sys/class/ieee80211 and checks whether
+/// "AP" is one of their supported interface modes
+fn probe_wifi_adapters() -> Vec<WifiAdapter> {
+    list_dir("/sys/class/ieee80211", "phy")
+        .into_iter()
+        .map(|phy| {
+            let interfaces = list_dir(&format!("/sys/class/ieee80211/{}/device/net", phy), "");
+            let ap_mode = match get_output(&["iw", "phy", &phy, "info"]) {
+                Ok(info) => supports_ap_mode(&info),
+                Err(err) => {
+                    warn!("failed to query wireless adapter {}: {}", phy, err);
+                    false
+                }
+            };
+            WifiAdapter {
+                phy,
+                interfaces,
+                ap_mode,
+            }
+        })
+        .collect()
+}
+
+/// Parses the "Supported interface modes" section of `iw phy <phy> info` e.g.
+///
+/// ```text
+///     Supported interface modes:
+///          * IBSS
+///          * managed
+///          * AP
+/// ```
+fn supports_ap_mode(info: &str) -> bool {
+    info.lines()
+        .skip_while(|line| !line.trim().starts_with("Supported interface modes:"))
+        .skip(1)
+        .take_while(|line| line.trim().starts_with('*'))
+        .any(|line| line.trim().trim_start_matches('*').trim() == "AP")
+}
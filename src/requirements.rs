@@ -0,0 +1,85 @@
+use clap::arg_enum;
+use log::info;
+
+use crate::apt::{install, installed_versions, PackageSpec};
+use crate::error::PeachConfigError;
+
+arg_enum! {
+    /// enum options for the set of system packages which setup installs
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Profile {
+        Minimal,
+        Developer
+    }
+}
+
+// System packages which the PeachCloud services need, installed on every device
+pub const MINIMAL_REQUIREMENTS: [&str; 9] = [
+    "locales",
+    "iw",
+    "python-smbus",
+    "i2c-tools",
+    "curl",
+    "libnss-resolve",
+    "sudo",
+    "nginx",
+    "wget",
+];
+
+// Tools for working on PeachCloud itself, only installed with the developer profile
+pub const DEVELOPER_REQUIREMENTS: [&str; 7] = [
+    "vim",
+    "man-db",
+    "git",
+    "build-essential",
+    "mosh",
+    "pkg-config",
+    "libssl-dev",
+];
+
+/// Returns the names of the system packages which the given profile requires
+pub fn requirements(profile: Profile) -> Vec<&'static str> {
+    let mut packages = MINIMAL_REQUIREMENTS.to_vec();
+    if profile == Profile::Developer {
+        packages.extend(DEVELOPER_REQUIREMENTS.iter());
+    }
+    packages
+}
+
+/// Returns the names of the system packages which the given profile requires
+/// and which dpkg does not report as fully installed
+pub fn missing_requirements(profile: Profile) -> Result<Vec<&'static str>, PeachConfigError> {
+    let packages = requirements(profile);
+    let installed = installed_versions(&packages)?;
+    Ok(packages
+        .into_iter()
+        .filter(|name| !installed.contains_key(*name))
+        .collect())
+}
+
+/// Installs the system packages which the given profile requires and which are not installed yet,
+/// leaving installed packages at their current version, and logs which packages were added
+///
+/// # Arguments
+///
+/// * `profile` - an enum, which selects the set of system packages to install
+///
+/// Returns the names of the packages which were installed.
+pub fn install_requirements(profile: Profile) -> Result<Vec<&'static str>, PeachConfigError> {
+    let missing = missing_requirements(profile)?;
+    if missing.is_empty() {
+        info!(
+            "all system requirements of the {} profile are already installed",
+            profile.to_string().to_lowercase()
+        );
+        return Ok(missing);
+    }
+    info!(
+        "installing missing system requirements: {}",
+        missing.join(", ")
+    );
+    let specs: Vec<PackageSpec> = missing.iter().map(|name| PackageSpec::new(name)).collect();
+    install(&specs, false)?;
+    info!("installed system requirements: {}", missing.join(", "));
+    Ok(missing)
+}
@@ -0,0 +1,100 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::apt::{install, PackageSpec};
+use crate::constants::ROLLBACK_FILE;
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+use crate::generate_manifest::get_currently_installed_microservices;
+use crate::utils::now_timestamp;
+
+/// The versions of the services which were installed before the last successful update
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RollbackPoint {
+    // time at which the versions were recorded, as an RFC 3339 timestamp in UTC
+    pub recorded_at: String,
+    // packages is a map of {package_name: version}
+    pub packages: HashMap<String, String>,
+}
+
+/// Returns the currently installed versions of the services,
+/// so that a following update can be rolled back
+pub fn current_rollback_point() -> Result<RollbackPoint, PeachConfigError> {
+    Ok(RollbackPoint {
+        recorded_at: now_timestamp(),
+        packages: get_currently_installed_microservices()?,
+    })
+}
+
+/// Saves the versions which were installed before an update, replacing the rollback point
+/// of the previous update. Only called once the update succeeded and passed its health checks,
+/// so that a failed update does not replace the versions of the last working update.
+pub fn save_rollback_point(rollback_point: &RollbackPoint) -> Result<(), PeachConfigError> {
+    let json_str = serde_json::to_string(rollback_point)?;
+    fs::write(ROLLBACK_FILE, json_str).context(FileWriteError {
+        file: ROLLBACK_FILE.to_string(),
+    })?;
+    Ok(())
+}
+
+/// Loads the versions which were recorded before the last successful update,
+/// returning Ok(None) if no update has been recorded
+pub fn load_rollback_point() -> Result<Option<RollbackPoint>, PeachConfigError> {
+    if !Path::new(ROLLBACK_FILE).exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(ROLLBACK_FILE).context(FileReadError {
+        file: ROLLBACK_FILE.to_string(),
+    })?;
+    let rollback_point: RollbackPoint = serde_json::from_str(&contents)?;
+    Ok(Some(rollback_point))
+}
+
+/// Reinstalls the versions of the services which were installed before the last successful update
+pub fn rollback() -> Result<(), PeachConfigError> {
+    match load_rollback_point()? {
+        Some(rollback_point) => rollback_to(&rollback_point),
+        None => Err(PeachConfigError::RollbackError {
+            msg: "no update has been recorded to roll back".to_string(),
+        }),
+    }
+}
+
+/// Reinstalls the versions of the services recorded in the given rollback point.
+///
+/// apt installs the previous versions from its cache in /var/cache/apt/archives if they
+/// are still there, and downloads them from the PeachCloud apt repository otherwise.
+/// Services which were not installed when the versions were recorded are left installed.
+pub fn rollback_to(rollback_point: &RollbackPoint) -> Result<(), PeachConfigError> {
+    info!(
+        "rolling back to versions recorded at {}",
+        rollback_point.recorded_at
+    );
+    let installed = get_currently_installed_microservices()?;
+    let mut specs: Vec<PackageSpec> = rollback_point
+        .packages
+        .iter()
+        .filter(|(name, version)| installed.get(*name) != Some(version))
+        .map(|(name, version)| PackageSpec::at(name, version))
+        .collect();
+    specs.sort_by(|a, b| a.name.cmp(&b.name));
+    for name in installed.keys() {
+        if !rollback_point.packages.contains_key(name) {
+            warn!(
+                "{} was not installed before the update, leaving it installed",
+                name
+            );
+        }
+    }
+    if specs.is_empty() {
+        info!("all services are already at their recorded versions");
+        return Ok(());
+    }
+    install(&specs, true)?;
+    let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+    info!("rolled back: {}", specs.join(", "));
+    Ok(())
+}
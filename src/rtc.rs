@@ -0,0 +1,174 @@
+use log::{info, warn};
+
+use crate::board::BoardProfile;
+use crate::error::PeachConfigError;
+use crate::i2c::{bus_exists, probe_addresses, read_register};
+use crate::utils::{cmd, get_output};
+use crate::RtcOption;
+
+// i2c addresses of the real-time clocks supported by peach-config
+pub const RTC_ADDRESSES: [u16; 3] = [0x51, 0x68, 0x6f];
+
+/// Kernel and device tree settings for a model of real-time clock
+#[derive(Debug, Clone, Copy)]
+pub struct RtcChip {
+    // device name which is written to /sys/class/i2c-adapter/i2c-N/new_device
+    pub device: &'static str,
+    // i2c address of the clock
+    pub address: u16,
+    // parameter of the i2c-rtc device tree overlay
+    pub overlay: &'static str,
+    // kernel module which provides the driver for the clock
+    pub module: &'static str,
+}
+
+/// Returns the settings for the given real-time clock model,
+/// or None if the model is RtcOption::Auto and still needs to be detected
+pub fn rtc_chip(model: &RtcOption) -> Option<RtcChip> {
+    let chip = match model {
+        RtcOption::DS1307 => RtcChip {
+            device: "ds1307",
+            address: 0x68,
+            overlay: "ds1307",
+            module: "rtc-ds1307",
+        },
+        RtcOption::DS3231 => RtcChip {
+            device: "ds3231",
+            address: 0x68,
+            overlay: "ds3231",
+            module: "rtc-ds1307",
+        },
+        RtcOption::PCF8523 => RtcChip {
+            device: "pcf8523",
+            address: 0x68,
+            overlay: "pcf8523",
+            module: "rtc-pcf8523",
+        },
+        RtcOption::PCF85063 => RtcChip {
+            device: "pcf85063",
+            address: 0x51,
+            overlay: "pcf85063",
+            module: "rtc-pcf85063",
+        },
+        RtcOption::MCP7940N => RtcChip {
+            device: "mcp7940x",
+            address: 0x6f,
+            overlay: "mcp7940x",
+            module: "rtc-ds1307",
+        },
+        RtcOption::Auto => return None,
+    };
+    Some(chip)
+}
+
+/// Resolves RtcOption::Auto to the detected real-time clock model,
+/// any other option is returned unchanged.
+pub fn resolve_rtc(
+    rtc: Option<RtcOption>,
+    profile: &BoardProfile,
+) -> Result<Option<RtcOption>, PeachConfigError> {
+    match rtc {
+        Some(RtcOption::Auto) => detect_rtc(profile),
+        other => Ok(other),
+    }
+}
+
+/// Probes the i2c bus of the board for the known addresses of real-time clocks.
+///
+/// Clocks at 0x51 are reported as PCF85063 and clocks at 0x6f as MCP7940N. DS1307, DS3231
+/// and PCF8523 all use address 0x68 and cannot be told apart reliably. A clock at 0x68 is only
+/// reported as a DS3231 if its control register holds the DS3231 reset value and the unused bits
+/// of its status and temperature registers read as zero, any other clock at 0x68 is reported as
+/// a DS1307. The DS1307 keeps user data in the same registers, which may happen to match, and a
+/// DS3231 whose control register was changed from its reset value is reported as a DS1307, so the
+/// chosen model is logged and a PCF8523 must always be selected explicitly.
+///
+/// Returns Ok(None) if no real-time clock was found.
+pub fn detect_rtc(profile: &BoardProfile) -> Result<Option<RtcOption>, PeachConfigError> {
+    let bus = match profile.i2c_bus {
+        Some(bus) => bus,
+        None => {
+            warn!(
+                "board {} has no i2c bus to probe for a real-time clock",
+                profile.board
+            );
+            return Ok(None);
+        }
+    };
+    // i2c-dev provides the /dev/i2c-N device files used by i2c-tools
+    cmd(&["modprobe", "i2c-dev"])?;
+    if !bus_exists(bus) {
+        warn!(
+            "i2c bus {} is not available, it may only be enabled after a reboot",
+            bus
+        );
+        return Ok(None);
+    }
+    let addresses = probe_addresses(bus, &RTC_ADDRESSES)?;
+    let detected = if addresses.contains(&0x68) {
+        if is_ds3231(bus) {
+            info!("clock at 0x68 has the control and status registers of a DS3231");
+            Some(RtcOption::DS3231)
+        } else {
+            info!(
+                "clock at 0x68 does not have the control and status registers of a DS3231, \
+                 assuming a DS1307, select another model with --rtc if this is wrong"
+            );
+            Some(RtcOption::DS1307)
+        }
+    } else if addresses.contains(&0x51) {
+        Some(RtcOption::PCF85063)
+    } else if addresses.contains(&0x6f) {
+        Some(RtcOption::MCP7940N)
+    } else {
+        None
+    };
+    match &detected {
+        Some(model) => info!("detected {} real-time clock on i2c bus {}", model, bus),
+        None => warn!("no real-time clock detected on i2c bus {}", bus),
+    }
+    Ok(detected)
+}
+
+/// Returns true if the registers of the clock at 0x68 on the given bus match those of a DS3231
+/// which is still in its reset state
+fn is_ds3231(bus: u8) -> bool {
+    // control register: INTCN, RS2 and RS1 are set on power up, all other bits are cleared
+    let control = read_register(bus, 0x68, 0x0e);
+    // status register: bits 6 to 4 are unused and always read as zero
+    let status = read_register(bus, 0x68, 0x0f);
+    // temperature register: the lower 6 bits of the fraction are unused and always read as zero
+    let temperature = read_register(bus, 0x68, 0x12);
+    match (control, status, temperature) {
+        (Ok(control), Ok(status), Ok(temperature)) => {
+            control == 0x1c && status & 0x70 == 0 && temperature & 0x3f == 0
+        }
+        _ => false,
+    }
+}
+
+/// Prints the time of the hardware clock alongside the system time
+pub fn rtc_status() -> Result<(), PeachConfigError> {
+    let hardware_time = get_output(&["hwclock", "--show"])?;
+    let system_time = get_output(&["date", "--iso-8601=seconds"])?;
+    println!("hardware clock: {}", hardware_time);
+    println!("system time:    {}", system_time);
+    Ok(())
+}
+
+/// Synchronises the hardware clock and the system time
+///
+/// # Arguments
+///
+/// * `to_system` - a bool, if true, the system time is set from the hardware clock,
+///   otherwise the hardware clock is set from the system time
+pub fn rtc_sync(to_system: bool) -> Result<(), PeachConfigError> {
+    if to_system {
+        info!("setting system time from hardware clock");
+        cmd(&["hwclock", "--hctosys"])?;
+    } else {
+        info!("setting hardware clock from system time");
+        cmd(&["hwclock", "--systohc"])?;
+    }
+    rtc_status()
+}
@@ -0,0 +1,84 @@
+use clap::arg_enum;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::fs;
+use std::path::Path;
+
+use crate::auto_update::MaintenanceWindow;
+use crate::constants::SETTINGS_FILE;
+use crate::error::{FileReadError, FileWriteError, PeachConfigError};
+
+arg_enum! {
+    /// Release channels of the PeachCloud apt repository,
+    /// from the most tested to the most recent packages
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Channel {
+        Stable,
+        Testing,
+        Nightly
+    }
+}
+
+// arg_enum! does not accept the #[default] attribute on variants
+#[allow(clippy::derivable_impls)]
+impl Default for Channel {
+    fn default() -> Self {
+        Channel::Stable
+    }
+}
+
+impl Channel {
+    /// Returns the component of the apt repository which the channel's packages are published in
+    pub fn component(&self) -> &'static str {
+        match self {
+            Channel::Stable => "main",
+            Channel::Testing => "testing",
+            Channel::Nightly => "nightly",
+        }
+    }
+}
+
+/// Settings chosen with peach-config commands after setup, which are kept across updates
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Settings {
+    // release channel which packages are installed from
+    #[serde(default)]
+    pub channel: Channel,
+    // url of an http proxy, such as apt-cacher-ng, which apt downloads packages through
+    #[serde(default)]
+    pub apt_proxy: Option<String>,
+    // url of a mirror of the PeachCloud apt repository, used instead of apt.peachcloud.org
+    #[serde(default)]
+    pub repo_url: Option<String>,
+    // seconds to wait for another package manager to release the dpkg lock
+    #[serde(default)]
+    pub apt_lock_timeout: Option<u64>,
+    // true if updates are applied automatically by the auto-update timer
+    #[serde(default)]
+    pub auto_update: bool,
+    // daily window in which automatic updates are applied
+    #[serde(default)]
+    pub maintenance_window: Option<MaintenanceWindow>,
+}
+
+/// Loads the settings saved by peach-config, returning the default settings if none were saved
+pub fn load_settings() -> Result<Settings, PeachConfigError> {
+    if !Path::new(SETTINGS_FILE).exists() {
+        return Ok(Settings::default());
+    }
+    let contents = fs::read_to_string(SETTINGS_FILE).context(FileReadError {
+        file: SETTINGS_FILE.to_string(),
+    })?;
+    let settings: Settings = serde_json::from_str(&contents)?;
+    Ok(settings)
+}
+
+/// Saves the settings to SETTINGS_FILE
+pub fn save_settings(settings: &Settings) -> Result<(), PeachConfigError> {
+    let json_str = serde_json::to_string(settings)?;
+    fs::write(SETTINGS_FILE, json_str).context(FileWriteError {
+        file: SETTINGS_FILE.to_string(),
+    })?;
+    Ok(())
+}
@@ -0,0 +1,347 @@
+use log::{info, warn};
+use snafu::ResultExt;
+use std::fs;
+use std::path::Path;
+
+use crate::board::{board_profile, detect_board, BoardProfile};
+use crate::config_txt::{ConfigTxt, Directive};
+use crate::error::{FileWriteError, PeachConfigError};
+use crate::generate_manifest::{load_hardware_config, save_hardware_config};
+use crate::kernel_modules::set_managed_modules;
+use crate::rtc::{resolve_rtc, rtc_chip, RtcChip};
+use crate::utils::{cmd, conf};
+use crate::{HardwareSetOpts, RtcChoice, RtcOption, Switch};
+
+// File path of the script which registers the real-time clock as an i2c device on boot
+pub const ACTIVATE_RTC_SCRIPT: &str = "/usr/local/bin/activate_rtc";
+
+// File path of the systemd unit which runs the activate_rtc script
+pub const ACTIVATE_RTC_SERVICE: &str = "/etc/systemd/system/activate-rtc.service";
+
+/// Installs the hardware specific configuration files for the given board profile.
+///
+/// # Arguments
+///
+/// * `profile` - the hardware profile of the board peach-config is running on
+/// * `i2c` - a bool, if true, setup i2c configurations for peach-menu
+/// * `rtc` - an optional enum, which if provided indicates the model number of the real-time
+///   clock being used
+pub fn configure_hardware(
+    profile: &BoardProfile,
+    i2c: bool,
+    rtc: &Option<RtcOption>,
+) -> Result<(), PeachConfigError> {
+    if let Some(gpio_rules) = profile.gpio_rules {
+        info!("[ CONFIGURING GPIO ]");
+        cmd(&[
+            "cp",
+            &conf(gpio_rules),
+            &format!("/etc/udev/rules.d/{}", gpio_rules),
+        ])?;
+    }
+
+    if let Some(firmware_dir) = profile.firmware_dir {
+        if !profile.firmware_assets.is_empty() {
+            info!("[ INSTALLING FIRMWARE ASSETS ]");
+            for asset in profile.firmware_assets {
+                cmd(&["cp", &conf(asset), &format!("{}/{}", firmware_dir, asset)])?;
+            }
+        }
+    }
+
+    let i2c_bus = match profile.i2c_bus {
+        Some(bus) => bus,
+        None => {
+            if i2c {
+                warn!(
+                    "i2c is not supported on board {}, skipping i2c configuration",
+                    profile.board
+                );
+            }
+            return Ok(());
+        }
+    };
+
+    // kernel modules which are loaded at boot time
+    let mut modules = Vec::new();
+    // the real-time clock requires i2c, so it is removed if either is disabled
+    let chip = rtc.as_ref().and_then(rtc_chip).filter(|_| i2c);
+
+    if i2c {
+        info!("[ CONFIGURING I2C ]");
+        if let (Some(firmware_dir), Some(overlay)) = (profile.firmware_dir, profile.gpio_overlay) {
+            let overlays_dir = format!("{}/overlays", firmware_dir);
+            cmd(&["mkdir", "-p", &overlays_dir])?;
+            cmd(&[
+                "cp",
+                &conf(overlay),
+                &format!("{}/{}", overlays_dir, overlay),
+            ])?;
+        }
+        modules.extend_from_slice(profile.i2c_modules);
+    }
+
+    match &chip {
+        Some(chip) => {
+            info!("[ CONFIGURING {} RTC MODULE ]", chip.device.to_uppercase());
+            if !modules.contains(&chip.module) {
+                modules.push(chip.module);
+            }
+            write_activate_rtc_script(i2c_bus, chip)?;
+            cmd(&["cp", &conf("activate-rtc.service"), ACTIVATE_RTC_SERVICE])?;
+            cmd(&["systemctl", "daemon-reload"])?;
+            cmd(&["systemctl", "enable", "activate-rtc"])?;
+        }
+        None => remove_activate_rtc_unit()?,
+    }
+
+    info!("[ CONFIGURING KERNEL MODULES ]");
+    set_managed_modules(&modules, true)?;
+
+    if let Some(config_txt_path) = profile.config_txt() {
+        info!("[ UPDATING {} ]", config_txt_path);
+        let mut config_txt = ConfigTxt::load(&config_txt_path)?;
+        config_txt.set_managed_directives(config_txt_directives(profile, i2c, chip.as_ref()));
+        config_txt.save(&config_txt_path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the directives which are written to the managed block of config.txt
+/// for the given i2c and real-time clock settings
+///
+/// # Arguments
+///
+/// * `profile` - the hardware profile of the board peach-config is running on
+/// * `i2c` - a bool, if true, i2c is enabled
+/// * `chip` - the settings of the real-time clock, if one is configured
+fn config_txt_directives(
+    profile: &BoardProfile,
+    i2c: bool,
+    chip: Option<&RtcChip>,
+) -> Vec<Directive> {
+    let mut directives = Vec::new();
+    if !i2c {
+        return directives;
+    }
+    directives.push(Directive::Setting {
+        key: "upstream_kernel".to_string(),
+        value: "1".to_string(),
+    });
+    directives.push(Directive::DtParam("i2c_arm=on".to_string()));
+    if let (Some(_), Some(overlay)) = (profile.firmware_dir, profile.gpio_overlay) {
+        // apply device tree overlay to enable pull-up resistors for buttons
+        directives.push(Directive::Setting {
+            key: "device_tree_overlay".to_string(),
+            value: format!("overlays/{}", overlay),
+        });
+    }
+    if let Some(chip) = chip {
+        directives.push(Directive::DtOverlay(format!("i2c-rtc,{}", chip.overlay)));
+    }
+    directives
+}
+
+/// Writes the script which registers the real-time clock on the given i2c bus
+fn write_activate_rtc_script(i2c_bus: u8, chip: &RtcChip) -> Result<(), PeachConfigError> {
+    let script = format!(
+        "#!/bin/bash\necho {} 0x{:02x} > /sys/class/i2c-adapter/i2c-{}/new_device\n",
+        chip.device, chip.address, i2c_bus
+    );
+    fs::write(ACTIVATE_RTC_SCRIPT, script).context(FileWriteError {
+        file: ACTIVATE_RTC_SCRIPT.to_string(),
+    })?;
+    cmd(&["chmod", "755", ACTIVATE_RTC_SCRIPT])?;
+    Ok(())
+}
+
+/// Disables and removes the activate-rtc unit and script if they were previously installed
+fn remove_activate_rtc_unit() -> Result<(), PeachConfigError> {
+    if Path::new(ACTIVATE_RTC_SERVICE).exists() {
+        info!("[ REMOVING RTC ACTIVATION SERVICE ]");
+        cmd(&["systemctl", "disable", "activate-rtc"])?;
+        fs::remove_file(ACTIVATE_RTC_SERVICE).context(FileWriteError {
+            file: ACTIVATE_RTC_SERVICE.to_string(),
+        })?;
+        cmd(&["systemctl", "daemon-reload"])?;
+    }
+    if Path::new(ACTIVATE_RTC_SCRIPT).exists() {
+        fs::remove_file(ACTIVATE_RTC_SCRIPT).context(FileWriteError {
+            file: ACTIVATE_RTC_SCRIPT.to_string(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Changes the i2c and real-time clock configuration of a device which has already been set up.
+///
+/// Options which are not passed keep the value from the saved HardwareConfig. If the
+/// resulting configuration differs from the saved one, the configuration for the new
+/// settings is applied (reverting overlays, kernel modules, the activate-rtc unit and
+/// config.txt directives which are no longer needed) and the HardwareConfig is saved.
+///
+/// # Arguments
+///
+/// * `opts` - a HardwareSetOpts object containing parsed CLI args
+pub fn set_hardware(opts: HardwareSetOpts) -> Result<(), PeachConfigError> {
+    let current = load_hardware_config()?;
+    let (current_i2c, current_rtc, current_board) = match current {
+        Some(config) => (config.i2c, config.rtc, config.board),
+        None => (false, None, None),
+    };
+
+    let board = match current_board {
+        Some(board) => board,
+        None => detect_board()?,
+    };
+    let profile = board_profile(board);
+
+    let rtc = match opts.rtc {
+        Some(RtcChoice::None) => Some(None),
+        Some(RtcChoice::Model(model)) => Some(resolve_rtc(Some(model), &profile)?),
+        None => None,
+    };
+    let (i2c, rtc) = merge_hardware_settings(current_i2c, current_rtc.clone(), opts.i2c, rtc);
+
+    if i2c == current_i2c && rtc == current_rtc && current_board.is_some() {
+        info!("hardware configuration is unchanged");
+        return Ok(());
+    }
+    if i2c != current_i2c {
+        info!("i2c: {} -> {}", switch_name(current_i2c), switch_name(i2c));
+    }
+    if rtc != current_rtc {
+        info!("rtc: {} -> {}", rtc_name(&current_rtc), rtc_name(&rtc));
+    }
+
+    configure_hardware(&profile, i2c, &rtc)?;
+    save_hardware_config(i2c, rtc, Some(board))?;
+    info!("[ HARDWARE CONFIGURATION UPDATED, please reboot your device ]");
+    Ok(())
+}
+
+/// Returns the i2c and real-time clock settings which `hardware set` applies, given the saved
+/// settings and the options which were passed. The real-time clock requires i2c, so it is
+/// cleared when i2c is off, and the saved HardwareConfig never records a clock without i2c.
+///
+/// # Arguments
+///
+/// * `current_i2c` - the saved i2c setting
+/// * `current_rtc` - the saved real-time clock
+/// * `i2c` - the i2c option, if it was passed
+/// * `rtc` - the resolved real-time clock option, if it was passed
+fn merge_hardware_settings(
+    current_i2c: bool,
+    current_rtc: Option<RtcOption>,
+    i2c: Option<Switch>,
+    rtc: Option<Option<RtcOption>>,
+) -> (bool, Option<RtcOption>) {
+    let i2c = match i2c {
+        Some(Switch::On) => true,
+        Some(Switch::Off) => false,
+        None => current_i2c,
+    };
+    let rtc = rtc.unwrap_or(current_rtc);
+    if rtc.is_some() && !i2c {
+        warn!("the real-time clock requires i2c, it is removed while i2c is off");
+        return (i2c, None);
+    }
+    (i2c, rtc)
+}
+
+fn switch_name(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn rtc_name(rtc: &Option<RtcOption>) -> String {
+    match rtc {
+        Some(model) => model.to_string().to_lowercase(),
+        None => "none".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Board;
+    use crate::config_txt::{MANAGED_BLOCK_BEGIN, MANAGED_BLOCK_END};
+
+    // config.txt as written by earlier versions of peach-config for a ds1307 clock
+    const LEGACY_DS1307_CONFIG_TXT: &str = "\
+arm_control=0x200
+enable_uart=1
+upstream_kernel=1
+dtparam=i2c_arm=on
+dtoverlay=i2c-rtc,ds1307
+device_tree_overlay=overlays/mygpio.dtbo
+kernel=vmlinuz-4.19.0-10-arm64
+initramfs initrd.img-4.19.0-10-arm64
+";
+
+    fn apply(contents: &str, i2c: bool, rtc: Option<RtcOption>) -> String {
+        let profile = board_profile(Board::Pi3);
+        let chip = rtc.as_ref().and_then(rtc_chip);
+        let mut config_txt = ConfigTxt::parse(contents);
+        config_txt.set_managed_directives(config_txt_directives(&profile, i2c, chip.as_ref()));
+        config_txt.to_string()
+    }
+
+    #[test]
+    fn changing_rtc_model_replaces_overlay() {
+        let contents = apply(LEGACY_DS1307_CONFIG_TXT, true, Some(RtcOption::DS3231));
+        assert_eq!(contents.matches("dtoverlay=i2c-rtc").count(), 1);
+        assert!(contents.contains("dtoverlay=i2c-rtc,ds3231"));
+        assert_eq!(contents.matches("upstream_kernel=1").count(), 1);
+        assert_eq!(contents.matches("dtparam=i2c_arm=on").count(), 1);
+        assert_eq!(contents.matches("device_tree_overlay=").count(), 1);
+
+        let contents = apply(&contents, true, Some(RtcOption::DS1307));
+        assert_eq!(contents.matches("dtoverlay=i2c-rtc").count(), 1);
+        assert!(contents.contains("dtoverlay=i2c-rtc,ds1307"));
+    }
+
+    #[test]
+    fn turning_i2c_off_clears_the_rtc() {
+        assert_eq!(
+            merge_hardware_settings(true, Some(RtcOption::DS3231), Some(Switch::Off), None),
+            (false, None)
+        );
+        assert_eq!(
+            merge_hardware_settings(true, None, Some(Switch::Off), Some(Some(RtcOption::DS1307))),
+            (false, None)
+        );
+        assert_eq!(
+            merge_hardware_settings(true, Some(RtcOption::DS3231), None, None),
+            (true, Some(RtcOption::DS3231))
+        );
+        assert_eq!(
+            merge_hardware_settings(false, None, Some(Switch::On), Some(Some(RtcOption::DS1307))),
+            (true, Some(RtcOption::DS1307))
+        );
+        assert_eq!(
+            merge_hardware_settings(true, Some(RtcOption::DS3231), None, Some(None)),
+            (true, None)
+        );
+    }
+
+    #[test]
+    fn disabling_rtc_and_i2c_reverts_config_txt() {
+        let contents = apply(LEGACY_DS1307_CONFIG_TXT, true, None);
+        assert!(!contents.contains("i2c-rtc"));
+        assert!(contents.contains("dtparam=i2c_arm=on"));
+
+        let contents = apply(LEGACY_DS1307_CONFIG_TXT, false, Some(RtcOption::DS1307));
+        assert_eq!(
+            contents,
+            format!(
+                "arm_control=0x200\nenable_uart=1\nkernel=vmlinuz-4.19.0-10-arm64\ninitramfs initrd.img-4.19.0-10-arm64\n\n{}\n[all]\n{}\n",
+                MANAGED_BLOCK_BEGIN, MANAGED_BLOCK_END
+            )
+        );
+    }
+}
@@ -1,47 +1,41 @@
 use log::info;
 use std::path::Path;
 
+use crate::apt::{remove, set_hold};
 use crate::error::PeachConfigError;
-use crate::utils::{cmd, conf};
+use crate::inventory::{
+    AP0_NETWORK, AP0_SERVICE, AP0_WPA_SUPPLICANT, AP_AUTO_DEPLOY_SCRIPT, AP_AUTO_DEPLOY_SERVICE,
+    AP_AUTO_DEPLOY_TIMER, COPY_WLAN_SCRIPT, COPY_WLAN_SERVICE, HELD_PACKAGES, HOSTNAME, HOSTS,
+    WIRED_NETWORK, WLAN0_NETWORK, WLAN0_WPA_SUPPLICANT,
+};
+use crate::utils::cmd;
 
 /// Idempotent script to configure a Debian installation to use
 /// systemd-networkd for general networking. The script configures the eth0,
 /// wlan0 and ap0 interfaces. This configuration allows switching between
 /// wireless client mode (wlan0) and wireless access point mode (ap0)
+///
+/// libnss-resolve, which systemd-resolved needs, is installed beforehand by setup
+/// as one of the system requirements.
 pub fn configure_networking() -> Result<(), PeachConfigError> {
-    info!("[ INSTALLING SYSTEM REQUIREMENTS ]");
-    cmd(&["apt", "install", "-y", "libnss-resolve"])?;
-
     info!("[ SETTING HOST ]");
-    cmd(&["cp", &conf("hostname"), "/etc/hostname"])?;
-    cmd(&["cp", &conf("hosts"), "/etc/hosts"])?;
+    HOSTNAME.install()?;
+    HOSTS.install()?;
 
     info!("[ DEINSTALLING CLASSIC NETWORKING ]");
-    cmd(&[
-        "apt-get",
-        "autoremove",
-        "-y",
-        "ifupdown",
-        "dhcpcd5",
-        "isc-dhcp-client",
-        "isc-dhcp-common",
-        "rsyslog",
-    ])?;
-    cmd(&[
-        "apt-mark",
-        "hold",
+    remove(&[
         "ifupdown",
         "dhcpcd5",
         "isc-dhcp-client",
         "isc-dhcp-common",
         "rsyslog",
-        "openresolv",
     ])?;
     cmd(&["rm", "-rf", "/etc/network", "/etc/dhcp"])?;
 
     info!("[ SETTING UP SYSTEMD-RESOLVED & SYSTEMD-NETWORKD ]");
-    cmd(&["apt-get", "autoremove", "-y", "avahi-daemon"])?;
-    cmd(&["apt-mark", "hold", "avahi-daemon", "libnss-mdns"])?;
+    remove(&["avahi-daemon"])?;
+    // held once removed, since apt will not remove a held package
+    set_hold(&HELD_PACKAGES, true)?;
     cmd(&[
         "ln",
         "-sf",
@@ -56,91 +50,42 @@ pub fn configure_networking() -> Result<(), PeachConfigError> {
     ])?;
 
     info!("[ CREATING INTERFACE FILE FOR WIRED CONNECTION ]");
-    cmd(&[
-        "cp",
-        &conf("network/04-wired.network"),
-        "/etc/systemd/network/04-wired.network",
-    ])?;
+    WIRED_NETWORK.install()?;
 
     info!("[ SETTING UP WPA_SUPPLICANT AS WIFI CLIENT WITH WLAN0 ]");
     // to avoid overwriting previous credentials, only copy file if it doesn't already exist
-    let wlan0 = "/etc/wpa_supplicant/wpa_supplicant-wlan0.conf";
-    if !Path::new(wlan0).exists() {
-        cmd(&["cp", &conf("network/wpa_supplicant-wlan0.conf"), wlan0])?;
-        cmd(&["chmod", "660", wlan0])?;
-        cmd(&["chown", "root:netdev", wlan0])?;
+    if !Path::new(WLAN0_WPA_SUPPLICANT.path).exists() {
+        WLAN0_WPA_SUPPLICANT.install()?;
     }
     cmd(&["systemctl", "disable", "wpa_supplicant.service"])?;
     cmd(&["systemctl", "enable", "wpa_supplicant@wlan0.service"])?;
 
     info!("[ CREATING BOOT SCRIPT TO COPY NETWORK CONFIGS ]");
-    cmd(&[
-        "cp",
-        &conf("network/copy-wlan.sh"),
-        "/usr/local/bin/copy-wlan.sh",
-    ])?;
-    cmd(&["chmod", "770", "/usr/local/bin/copy-wlan.sh"])?;
-    cmd(&[
-        "cp",
-        &conf("network/copy-wlan.service"),
-        "/etc/systemd/system/copy-wlan.service",
-    ])?;
+    COPY_WLAN_SCRIPT.install()?;
+    COPY_WLAN_SERVICE.install()?;
     cmd(&["systemctl", "enable", "copy-wlan.service"])?;
 
     info!("[ SETTING UP WPA_SUPPLICANT AS ACCESS POINT WITH AP0 ]");
-    cmd(&[
-        "cp",
-        &conf("network/wpa_supplicant-ap0.conf"),
-        "/etc/wpa_supplicant/wpa_supplicant-ap0.conf",
-    ])?;
-    cmd(&[
-        "chmod",
-        "600",
-        "/etc/wpa_supplicant/wpa_supplicant-ap0.conf",
-    ])?;
+    AP0_WPA_SUPPLICANT.install()?;
 
     info!("[ CONFIGURING INTERFACES ]");
-    cmd(&[
-        "cp",
-        &conf("network/08-wlan0.network"),
-        "/etc/systemd/network/08-wlan0.network",
-    ])?;
-    cmd(&[
-        "cp",
-        &conf("network/12-ap0.network"),
-        "/etc/systemd/network/12-ap0.network",
-    ])?;
+    WLAN0_NETWORK.install()?;
+    AP0_NETWORK.install()?;
 
     info!("[ MODIFYING SERVICE FOR ACCESS POINT TO USE AP0 ]");
     cmd(&["systemctl", "disable", "wpa_supplicant@ap0.service"])?;
-    cmd(&[
-        "cp",
-        &conf("network/wpa_supplicant@ap0.service"),
-        "/etc/systemd/system/wpa_supplicant@ap0.service",
-    ])?;
+    AP0_SERVICE.install()?;
 
     info!("[ SETTING WLAN0 TO RUN AS CLIENT ON STARTUP ]");
     cmd(&["systemctl", "enable", "wpa_supplicant@wlan0.service"])?;
     cmd(&["systemctl", "disable", "wpa_supplicant@ap0.service"])?;
 
     info!("[ CREATING ACCESS POINT AUTO-DEPLOY SCRIPT ]");
-    cmd(&[
-        "cp",
-        &conf("ap_auto_deploy.sh"),
-        "/usr/local/bin/ap_auto_deploy",
-    ])?;
+    AP_AUTO_DEPLOY_SCRIPT.install()?;
 
     info!("[ CONFIGURING ACCESS POINT AUTO-DEPLOY SERVICE ]");
-    cmd(&[
-        "cp",
-        &conf("network/ap-auto-deploy.service"),
-        "/etc/systemd/system/ap-auto-deploy.service",
-    ])?;
-    cmd(&[
-        "cp",
-        &conf("network/ap-auto-deploy.timer"),
-        "/etc/systemd/system/ap-auto-deploy.timer",
-    ])?;
+    AP_AUTO_DEPLOY_SERVICE.install()?;
+    AP_AUTO_DEPLOY_TIMER.install()?;
 
     info!("[ NETWORKING HAS BEEN CONFIGURED ]");
     Ok(())
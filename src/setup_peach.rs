@@ -1,13 +1,22 @@
-use log::info;
+use log::{info, warn};
 use snafu::ResultExt;
 use std::fs;
 
+use crate::board::{board_profile, detect_board, Board};
 use crate::error::{FileWriteError, PeachConfigError};
 use crate::generate_manifest::save_hardware_config;
+use crate::health::{print_health_table, run_health_checks};
+use crate::history::{record_history, Trigger};
+use crate::inventory::{
+    GROUPS, NGINX_SITE, NGINX_SITE_LINK, PEACH_USER, SERVICE_USERS, SUDOERS_FILE,
+};
+use crate::requirements::{install_requirements, Profile};
+use crate::rtc::resolve_rtc;
+use crate::setup_hardware::configure_hardware;
 use crate::setup_networking::configure_networking;
 use crate::setup_peach_deb::setup_peach_deb;
 use crate::update::update_microservices;
-use crate::utils::{cmd, conf, create_group_if_doesnt_exist, does_user_exist, get_output};
+use crate::utils::{cmd, create_group_if_doesnt_exist, does_user_exist, get_output};
 use crate::RtcOption;
 
 /// Idempotent setup of PeachCloud device which sets up networking configuration,
@@ -20,7 +29,12 @@ use crate::RtcOption;
 /// * `default_locale` - a bool, if true, sets the default locale of the device to en_US.UTF-8
 /// * `i2c` - a bool, if true, setup i2c configurations for peach-menu
 /// * `rtc` - an optional enum, which if provided indicates the model number of the real-time
-///           clock being used
+///   clock being used, or that the model should be detected by probing the i2c bus
+/// * `board` - an optional enum, which if provided overrides the detected hardware board
+/// * `profile` - an enum, which selects the set of system packages to install. Only packages
+///   which are not installed yet are installed
+///
+/// The installed versions of the services before and after setup are recorded in the update history.
 ///
 /// If any command in the script returns an error (non-zero exit status) a PeachConfigError
 /// is returned, otherwise an Ok is returned.
@@ -29,19 +43,23 @@ pub fn setup_peach(
     default_locale: bool,
     i2c: bool,
     rtc: Option<RtcOption>,
+    board: Option<Board>,
+    profile: Profile,
 ) -> Result<(), PeachConfigError> {
-    info!("[ RUNNING SETUP PEACH ]");
+    record_history(Trigger::Setup, || {
+        run_setup(no_input, default_locale, i2c, rtc, board, profile)
+    })
+}
 
-    // list of system users for (micro)services
-    let users = [
-        "peach-buttons",
-        "peach-menu",
-        "peach-monitor",
-        "peach-network",
-        "peach-oled",
-        "peach-stats",
-        "peach-web",
-    ];
+fn run_setup(
+    no_input: bool,
+    default_locale: bool,
+    i2c: bool,
+    rtc: Option<RtcOption>,
+    board: Option<Board>,
+    profile: Profile,
+) -> Result<(), PeachConfigError> {
+    info!("[ RUNNING SETUP PEACH ]");
 
     // Update Pi and install requirements
     info!("[ UPDATING OPERATING SYSTEM ]");
@@ -49,32 +67,13 @@ pub fn setup_peach(
     //    cmd(&["apt-get", "upgrade", "-y"])?;
 
     info!("[ INSTALLING SYSTEM REQUIREMENTS ]");
-    cmd(&[
-        "apt-get",
-        "install",
-        "vim",
-        "man-db",
-        "locales",
-        "iw",
-        "git",
-        "python-smbus",
-        "i2c-tools",
-        "build-essential",
-        "curl",
-        "libnss-resolve",
-        "mosh",
-        "sudo",
-        "pkg-config",
-        "libssl-dev",
-        "nginx",
-        "wget",
-        "-y",
-    ])?;
+    install_requirements(profile)?;
 
     // Create system groups first
     info!("[ CREATING SYSTEM GROUPS ]");
-    create_group_if_doesnt_exist("peach")?;
-    create_group_if_doesnt_exist("gpio-user")?;
+    for group in GROUPS {
+        create_group_if_doesnt_exist(group)?;
+    }
 
     //  Add the system users
     info!("[ ADDING SYSTEM USER ]");
@@ -83,7 +82,7 @@ pub fn setup_peach(
         let default_password = "peachcloud";
         let enc_password = get_output(&["openssl", "passwd", "-crypt", default_password])?;
         info!("[ CREATING SYSTEM USER WITH DEFAULT PASSWORD ]");
-        if !(does_user_exist("peach")?) {
+        if !(does_user_exist(PEACH_USER)?) {
             cmd(&[
                 "/usr/sbin/useradd",
                 "-m",
@@ -93,18 +92,18 @@ pub fn setup_peach(
                 "peach",
                 "-s",
                 "/bin/bash",
-                "peach",
+                PEACH_USER,
             ])?;
         }
     } else {
-        cmd(&["/usr/sbin/adduser", "peach"])?;
+        cmd(&["/usr/sbin/adduser", PEACH_USER])?;
     }
-    cmd(&["usermod", "-aG", "sudo", "peach"])?;
-    cmd(&["usermod", "-aG", "peach", "peach"])?;
+    cmd(&["usermod", "-aG", "sudo", PEACH_USER])?;
+    cmd(&["usermod", "-aG", "peach", PEACH_USER])?;
 
     info!("[ CREATING SYSTEM USERS ]");
     //  Peachcloud microservice users
-    for user in users {
+    for user in SERVICE_USERS {
         //  Create new system user without home directory and add to `peach` group
         cmd(&[
             "/usr/sbin/adduser",
@@ -129,73 +128,21 @@ pub fn setup_peach(
 
     //  Overwrite configuration files
     info!("[ CONFIGURING OPERATING SYSTEM ]");
-    info!("[ CONFIGURING GPIO ]");
-    cmd(&[
-        "cp",
-        &conf("50-gpio.rules"),
-        "/etc/udev/rules.d/50-gpio.rules",
-    ])?;
-
-    if i2c {
-        info!("[ CONFIGURING I2C ]");
-        cmd(&["mkdir", "-p", "/boot/firmware/overlays"])?;
-        cmd(&[
-            "cp",
-            &conf("mygpio.dtbo"),
-            "/boot/firmware/overlays/mygpio.dtbo",
-        ])?;
-        cmd(&["cp", &conf("config.txt_i2c"), "/boot/firmware/config.txt"])?;
-        cmd(&["cp", &conf("modules"), "/etc/modules"])?;
-    }
-
-    if let Some(rtc_model) = &rtc {
-        if i2c {
-            match rtc_model {
-                RtcOption::DS1307 => {
-                    info!("[ CONFIGURING DS1307 RTC MODULE ]");
-                    cmd(&[
-                        "cp",
-                        &conf("config.txt_ds1307"),
-                        "/boot/firmware/config.txt",
-                    ])?;
-                }
-                RtcOption::DS3231 => {
-                    info!("[ CONFIGURING DS3231 RTC MODULE ]");
-                    cmd(&[
-                        "cp",
-                        &conf("config.txt_ds3231"),
-                        "/boot/firmware/config.txt",
-                    ])?;
-                }
-            }
-            cmd(&["cp", &conf("modules_rtc"), "/etc/modules"])?;
-            cmd(&[
-                "cp",
-                &conf("activate_rtc.sh"),
-                "/usr/local/bin/activate_rtc",
-            ])?;
-            cmd(&[
-                "cp",
-                &conf("activate-rtc.service"),
-                "/etc/systemd/system/activate-rtc.service",
-            ])?;
-            cmd(&["systemctl", "daemon-reload"])?;
-            cmd(&["systemctl", "enable", "activate-rtc"])?;
-        }
-    }
+    let board = match board {
+        Some(board) => board,
+        None => detect_board()?,
+    };
+    info!(
+        "[ CONFIGURING HARDWARE FOR {} ]",
+        board.to_string().to_uppercase()
+    );
+    let profile = board_profile(board);
+    let rtc = resolve_rtc(rtc, &profile)?;
+    configure_hardware(&profile, i2c, &rtc)?;
 
     info!("[ CONFIGURING NGINX ]");
-    cmd(&[
-        "cp",
-        &conf("peach.conf"),
-        "/etc/nginx/sites-available/peach.conf",
-    ])?;
-    cmd(&[
-        "ln",
-        "-sf",
-        "/etc/nginx/sites-available/peach.conf",
-        "/etc/nginx/sites-enabled/",
-    ])?;
+    NGINX_SITE.install()?;
+    cmd(&["ln", "-sf", NGINX_SITE.path, NGINX_SITE_LINK])?;
 
     if !no_input {
         info!("[ CONFIGURING LOCALE ]");
@@ -224,7 +171,7 @@ pub fn setup_peach(
 
     info!("[ CONFIGURING SUDOERS ]");
     cmd(&["mkdir", "-p", "/etc/sudoers.d"])?;
-    cmd(&["cp", &conf("shutdown"), "/etc/sudoers.d/shutdown"])?;
+    SUDOERS_FILE.install()?;
 
     info!("[ CONFIGURING PEACH APT REPO ]");
     setup_peach_deb()?;
@@ -236,7 +183,19 @@ pub fn setup_peach(
     configure_networking()?;
 
     info!("[ SAVING LOG OF HARDWARE CONFIGURATIONS ]");
-    save_hardware_config(i2c, rtc)?;
+    save_hardware_config(i2c, rtc, Some(board))?;
+
+    // services which need the i2c bus may only start after a reboot,
+    // so a failed health check is reported without failing setup
+    info!("[ CHECKING HEALTH OF PEACH MICROSERVICES ]");
+    let report = run_health_checks();
+    print_health_table(&report);
+    if let Err(err) = report.ensure_healthy() {
+        warn!(
+            "{}, check again after rebooting with peach-config health",
+            err
+        );
+    }
 
     info!("[ PEACHCLOUD SETUP COMPLETE ]");
     info!("[ ------------------------- ]");
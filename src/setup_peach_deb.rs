@@ -1,20 +1,262 @@
-use crate::error::PeachConfigError;
-use crate::utils::{cmd, conf};
+use log::{info, warn};
+use snafu::ResultExt;
+use std::fs;
+use std::path::Path;
 
-/// Adds apt.peachcloud.org to the list of debian apt sources and sets the public key appropriately
-pub fn setup_peach_deb() -> Result<(), PeachConfigError> {
+use crate::apt::refresh;
+use crate::error::{FileWriteError, PeachConfigError};
+use crate::os_release::read_os_release;
+use crate::settings::{load_settings, save_settings, Channel, Settings};
+use crate::utils::{cmd, conf, get_output, TempDir};
+use crate::{AptSetOpts, AptUrl};
+
+// File which apt reads the PeachCloud repository from, in the deb822 format
+pub const APT_SOURCE_FILE: &str = "/etc/apt/sources.list.d/peachcloud.sources";
+
+// One-line source written by earlier versions of peach-config, which is replaced by APT_SOURCE_FILE
+const LEGACY_APT_SOURCE_FILE: &str = "/etc/apt/sources.list.d/peach.list";
+
+// Url of the PeachCloud apt repository, used unless a mirror is configured
+pub const DEFAULT_REPO_URL: &str = "http://apt.peachcloud.org/";
+
+// apt configuration snippet which sets the proxy apt downloads through
+pub const APT_PROXY_FILE: &str = "/etc/apt/apt.conf.d/01peachcloud-proxy";
+
+// Keyring which apt uses to verify the PeachCloud repository, and only that repository
+pub const KEYRING_FILE: &str = "/usr/share/keyrings/peachcloud.gpg";
+
+// Public key of the PeachCloud repository, used if it is not shipped in the conf directory
+const KEY_URL: &str = "https://apt.peachcloud.org/pubkey.gpg";
+
+// Fingerprint of the PeachCloud repository signing key, which a shipped or downloaded key must match.
+// PEACHCLOUD_KEY_FINGERPRINT in install.sh and the installation instructions in the README check
+// against the same value, so all three must be changed together.
+// TODO: replace with the fingerprint of the apt.peachcloud.org signing key, and ship the key as
+// conf/pubkey.gpg, before release. Until then every key is rejected.
+pub const KEY_FINGERPRINT: &str = "0000000000000000000000000000000000000000";
+
+// Debian releases which the PeachCloud apt repository publishes packages for, oldest first.
+// install.sh writes the first source of the repository from the same list.
+pub const SUPPORTED_DISTRIBUTIONS: [&str; 3] = ["buster", "bullseye", "bookworm"];
+
+/// Returns the distribution of the PeachCloud apt repository matching the Debian release
+/// in /etc/os-release. If the release is unknown or not supported, the newest supported
+/// distribution is used.
+pub fn apt_distribution() -> &'static str {
+    let newest = SUPPORTED_DISTRIBUTIONS[SUPPORTED_DISTRIBUTIONS.len() - 1];
+    let codename = read_os_release().and_then(|os_release| os_release.version_codename);
+    match codename {
+        Some(codename) => match SUPPORTED_DISTRIBUTIONS.iter().find(|&&d| d == codename) {
+            Some(distribution) => distribution,
+            None => {
+                warn!(
+                    "{} is not supported by the PeachCloud apt repository, using {}",
+                    codename, newest
+                );
+                newest
+            }
+        },
+        None => {
+            warn!("could not read the Debian release, using {}", newest);
+            newest
+        }
+    }
+}
+
+/// Writes the apt source of the PeachCloud repository, or the configured mirror of it, for the
+/// saved channel and the distribution of the running system, signed by the PeachCloud keyring
+pub fn write_apt_source(settings: &Settings) -> Result<(), PeachConfigError> {
+    let source = format!(
+        "Types: deb\n\
+         URIs: {}\n\
+         Suites: {}\n\
+         Components: {}\n\
+         Signed-By: {}\n",
+        settings.repo_url.as_deref().unwrap_or(DEFAULT_REPO_URL),
+        apt_distribution(),
+        settings.channel.component(),
+        KEYRING_FILE
+    );
+    fs::write(APT_SOURCE_FILE, source).context(FileWriteError {
+        file: APT_SOURCE_FILE.to_string(),
+    })?;
+    if Path::new(LEGACY_APT_SOURCE_FILE).exists() {
+        fs::remove_file(LEGACY_APT_SOURCE_FILE).context(FileWriteError {
+            file: LEGACY_APT_SOURCE_FILE.to_string(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Writes the apt configuration snippet which sets the proxy apt downloads through,
+/// or removes it if no proxy is configured
+pub fn write_apt_proxy(settings: &Settings) -> Result<(), PeachConfigError> {
+    match &settings.apt_proxy {
+        Some(proxy) => {
+            // repositories served over https are reached through the proxy with CONNECT
+            let snippet = format!(
+                "Acquire::http::Proxy \"{0}\";\nAcquire::https::Proxy \"{0}\";\n",
+                proxy
+            );
+            fs::write(APT_PROXY_FILE, snippet).context(FileWriteError {
+                file: APT_PROXY_FILE.to_string(),
+            })?;
+        }
+        None => {
+            if Path::new(APT_PROXY_FILE).exists() {
+                fs::remove_file(APT_PROXY_FILE).context(FileWriteError {
+                    file: APT_PROXY_FILE.to_string(),
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the fingerprints of the primary keys in the given gpg home directory
+fn key_fingerprints(gnupg_home: &str) -> Result<Vec<String>, PeachConfigError> {
+    let output = get_output(&[
+        "gpg",
+        "--batch",
+        "--homedir",
+        gnupg_home,
+        "--with-colons",
+        "--list-keys",
+    ])?;
+    // each primary key is a "pub" record followed by its "fpr" record,
+    // whose tenth field is the fingerprint
+    let mut fingerprints = Vec::new();
+    let mut after_pub = false;
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "pub" => after_pub = true,
+            "fpr" if after_pub => {
+                if let Some(fingerprint) = fields.get(9) {
+                    fingerprints.push(fingerprint.to_string());
+                }
+                after_pub = false;
+            }
+            _ => {}
+        }
+    }
+    Ok(fingerprints)
+}
+
+/// Installs the public key of the PeachCloud repository to KEYRING_FILE.
+///
+/// The key shipped in the conf directory of the peach-config package is used if present,
+/// otherwise it is downloaded over HTTPS. Either way it is only installed if it is the single
+/// key with the fingerprint KEY_FINGERPRINT, and it is trusted for the PeachCloud repository only.
+/// The key is downloaded and imported in a private temporary directory, which is removed afterwards.
+pub fn install_keyring() -> Result<(), PeachConfigError> {
+    let temp_dir = TempDir::new()?;
+    let shipped_key = conf("pubkey.gpg");
+    let key_file = if Path::new(&shipped_key).exists() {
+        shipped_key
+    } else {
+        let downloaded_key = temp_dir.join("pubkey.gpg");
+        cmd(&["wget", "-q", "-O", &downloaded_key, KEY_URL])?;
+        downloaded_key
+    };
+
+    // import the key into an empty gpg home to read its fingerprint and export it as a keyring
+    let gnupg_home = temp_dir.join("gnupg");
+    cmd(&["install", "-d", "-m", "700", &gnupg_home])?;
     cmd(&[
-        "cp",
-        &conf("peach.list"),
-        "/etc/apt/sources.list.d/peach.list",
+        "gpg",
+        "--batch",
+        "--homedir",
+        &gnupg_home,
+        "--import",
+        &key_file,
     ])?;
+    let fingerprints = key_fingerprints(&gnupg_home)?;
+    if fingerprints != [KEY_FINGERPRINT] {
+        return Err(PeachConfigError::KeyVerificationError {
+            expected: KEY_FINGERPRINT.to_string(),
+            found: fingerprints.join(", "),
+        });
+    }
+    info!("verified PeachCloud repository key {}", KEY_FINGERPRINT);
     cmd(&[
-        "wget",
-        "-O",
-        "/tmp/pubkey.gpg",
-        "http://apt.peachcloud.org/pubkey.gpg",
+        "gpg",
+        "--batch",
+        "--yes",
+        "--homedir",
+        &gnupg_home,
+        "--output",
+        KEYRING_FILE,
+        "--export",
+        KEY_FINGERPRINT,
     ])?;
-    cmd(&["apt-key", "add", "/tmp/pubkey.gpg"])?;
-    cmd(&["rm", "/tmp/pubkey.gpg"])?;
+    cmd(&["chmod", "644", KEYRING_FILE])?;
+
+    // earlier versions of peach-config added the key with apt-key,
+    // which made apt trust it for every repository
+    if Path::new("/usr/bin/apt-key").exists() && cmd(&["apt-key", "del", KEY_FINGERPRINT]).is_err()
+    {
+        warn!("could not remove the PeachCloud key from the apt-key keyring");
+    }
+    Ok(())
+}
+
+/// Rewrites the apt source and proxy from the saved settings and downloads the package lists,
+/// so that apt installs packages from the channel, mirror and distribution currently in use
+pub fn update_package_lists() -> Result<(), PeachConfigError> {
+    if !Path::new(KEYRING_FILE).exists() {
+        install_keyring()?;
+    }
+    let settings = load_settings()?;
+    write_apt_proxy(&settings)?;
+    write_apt_source(&settings)?;
+    refresh()?;
+    Ok(())
+}
+
+/// Switches the release channel which PeachCloud packages are installed from.
+/// Packages are not changed until the next update.
+pub fn set_channel(channel: Channel) -> Result<(), PeachConfigError> {
+    let mut settings = load_settings()?;
+    settings.channel = channel;
+    save_settings(&settings)?;
+    update_package_lists()
+}
+
+/// Changes the proxy apt downloads through, the mirror of the PeachCloud repository packages
+/// are installed from and how long to wait for the dpkg lock. Options which are not passed
+/// keep their saved value.
+///
+/// # Arguments
+///
+/// * `opts` - an AptSetOpts object containing parsed CLI args
+pub fn set_apt(opts: AptSetOpts) -> Result<(), PeachConfigError> {
+    let mut settings = load_settings()?;
+    if let Some(proxy) = opts.proxy {
+        settings.apt_proxy = match proxy {
+            AptUrl::Default => None,
+            AptUrl::Url(url) => Some(url),
+        };
+    }
+    if let Some(lock_timeout) = opts.lock_timeout {
+        settings.apt_lock_timeout = Some(lock_timeout);
+    }
+    if let Some(repo) = opts.repo {
+        settings.repo_url = match repo {
+            AptUrl::Default => None,
+            AptUrl::Url(url) => Some(url),
+        };
+    }
+    save_settings(&settings)?;
+    update_package_lists()
+}
+
+/// Adds apt.peachcloud.org to the debian apt sources and installs its public key to a keyring
+/// which apt only uses for that repository
+pub fn setup_peach_deb() -> Result<(), PeachConfigError> {
+    install_keyring()?;
+    let settings = load_settings()?;
+    write_apt_proxy(&settings)?;
+    write_apt_source(&settings)?;
     Ok(())
 }
@@ -0,0 +1,196 @@
+use log::{info, warn};
+use snafu::ResultExt;
+use std::fs;
+use std::path::Path;
+
+use crate::apt::{installed_versions, purge, set_hold};
+use crate::auto_update::disable_auto_update;
+use crate::board::{board_profile, detect_board};
+use crate::config_txt::ConfigTxt;
+use crate::constants::{HARDWARE_CONFIG_FILE, SERVICES};
+use crate::error::{FileWriteError, PeachConfigError};
+use crate::generate_manifest::load_hardware_config;
+use crate::health::is_unit_active;
+use crate::inventory::{
+    APT_FILES, ENABLED_UNITS, GROUPS, HELD_PACKAGES, NGINX_SITE, NGINX_SITE_LINK, PEACH_USER,
+    RESET_FILES, SERVICE_USERS, STATE_FILES, UNINSTALLED_FILES,
+};
+use crate::kernel_modules::{set_managed_modules, MODULES_LOAD_FILE};
+use crate::pinning::{get_pinned_services, unpin_service};
+use crate::settings::Settings;
+use crate::setup_peach_deb::{write_apt_proxy, write_apt_source};
+use crate::utils::{cmd, confirm, does_group_exist, does_user_exist};
+
+// Directory which systemd units installed by setup are written to
+const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Removes a file if it exists
+fn remove_file_if_exists(path: &str) -> Result<(), PeachConfigError> {
+    if Path::new(path).exists() {
+        info!("removing {}", path);
+        fs::remove_file(path).context(FileWriteError {
+            file: path.to_string(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Turns off automatic updates and releases every pinned service
+fn reset_update_settings() -> Result<(), PeachConfigError> {
+    info!("[ DISABLING AUTOMATIC UPDATES ]");
+    disable_auto_update()?;
+    info!("[ RELEASING PINNED SERVICES ]");
+    for service in get_pinned_services()?.keys() {
+        unpin_service(service)?;
+    }
+    Ok(())
+}
+
+/// Removes the kernel modules, config.txt directives and udev rules which setup
+/// installed for the board peach-config is running on
+fn revert_hardware() -> Result<(), PeachConfigError> {
+    let board = match load_hardware_config()?.and_then(|config| config.board) {
+        Some(board) => board,
+        None => detect_board()?,
+    };
+    let profile = board_profile(board);
+    set_managed_modules(&[], false)?;
+    remove_file_if_exists(MODULES_LOAD_FILE)?;
+    if let Some(config_txt_path) = profile.config_txt() {
+        if Path::new(&config_txt_path).exists() {
+            let mut config_txt = ConfigTxt::load(&config_txt_path)?;
+            config_txt.remove_managed_block();
+            config_txt.save(&config_txt_path)?;
+        }
+    }
+    if let Some(gpio_rules) = profile.gpio_rules {
+        remove_file_if_exists(&format!("/etc/udev/rules.d/{}", gpio_rules))?;
+    }
+    Ok(())
+}
+
+/// Removes PeachCloud from the device, keeping the operating system.
+///
+/// The microservices are purged, and the users, groups, systemd units, nginx site, sudoers
+/// drop-in, apt repository and package holds which setup added are removed, along with the
+/// settings and records of peach-config. Networking configuration is left in place so that
+/// the device stays reachable, and peach-config itself stays installed.
+///
+/// # Arguments
+///
+/// * `no_input` - a bool, if true, uninstalls without asking for confirmation
+pub fn uninstall(no_input: bool) -> Result<(), PeachConfigError> {
+    if !no_input
+        && !confirm(
+            "This removes the PeachCloud services, users and configuration from this device. Continue?",
+        )?
+    {
+        println!("uninstall cancelled");
+        return Ok(());
+    }
+    info!("[ RUNNING UNINSTALL PEACH ]");
+    reset_update_settings()?;
+
+    info!("[ REMOVING PEACH MICROSERVICES ]");
+    let installed = installed_versions(&SERVICES)?;
+    let services: Vec<&str> = SERVICES
+        .iter()
+        .copied()
+        .filter(|service| *service != "peach-config" && installed.contains_key(*service))
+        .collect();
+    purge(&services)?;
+
+    info!("[ REVERTING HARDWARE CONFIGURATION ]");
+    revert_hardware()?;
+
+    info!("[ REMOVING SYSTEMD UNITS, NGINX SITE AND SUDOERS ]");
+    for unit in ENABLED_UNITS {
+        if Path::new(&format!("{}/{}", SYSTEMD_UNIT_DIR, unit)).exists() {
+            cmd(&["systemctl", "disable", unit])?;
+        }
+    }
+    for file in UNINSTALLED_FILES {
+        remove_file_if_exists(file)?;
+    }
+    cmd(&["systemctl", "daemon-reload"])?;
+    if is_unit_active("nginx") {
+        cmd(&["systemctl", "reload", "nginx"])?;
+    }
+
+    info!("[ REMOVING PEACH APT REPO ]");
+    for file in APT_FILES {
+        remove_file_if_exists(file)?;
+    }
+
+    info!("[ RELEASING HELD PACKAGES ]");
+    set_hold(&HELD_PACKAGES, false)?;
+
+    // a user who is logged in cannot be removed, so failures are reported without stopping
+    info!("[ REMOVING SYSTEM USERS AND GROUPS ]");
+    for user in SERVICE_USERS.iter().chain(&[PEACH_USER]) {
+        if does_user_exist(user)? {
+            if let Err(err) = cmd(&["/usr/sbin/deluser", user]) {
+                warn!("could not remove user {}: {}", user, err);
+            }
+        }
+    }
+    for group in GROUPS {
+        if does_group_exist(group)? {
+            if let Err(err) = cmd(&["/usr/sbin/delgroup", group]) {
+                warn!("could not remove group {}: {}", group, err);
+            }
+        }
+    }
+
+    info!("[ REMOVING PEACH-CONFIG SETTINGS ]");
+    for file in STATE_FILES.iter().chain(&[HARDWARE_CONFIG_FILE]) {
+        remove_file_if_exists(file)?;
+    }
+
+    println!("PeachCloud has been uninstalled, remove peach-config itself with: apt-get purge peach-config");
+    Ok(())
+}
+
+/// Restores the PeachCloud configuration of the device to the defaults shipped with peach-config,
+/// keeping the operating system and the installed microservices.
+///
+/// The hostname, nginx site, sudoers drop-in, network interfaces, Wi-Fi credentials and access
+/// point configuration are copied from the conf dir again, automatic updates and pins are turned
+/// off, the apt repository is reset to the stable channel of apt.peachcloud.org, and the settings,
+/// update history and rollback point of peach-config are removed.
+///
+/// # Arguments
+///
+/// * `no_input` - a bool, if true, resets without asking for confirmation
+pub fn factory_reset(no_input: bool) -> Result<(), PeachConfigError> {
+    if !no_input
+        && !confirm(
+            "This resets the PeachCloud configuration of this device, including Wi-Fi credentials. Continue?",
+        )?
+    {
+        println!("factory reset cancelled");
+        return Ok(());
+    }
+    info!("[ RUNNING FACTORY RESET ]");
+    reset_update_settings()?;
+
+    info!("[ REMOVING PEACH-CONFIG SETTINGS ]");
+    for file in STATE_FILES {
+        remove_file_if_exists(file)?;
+    }
+
+    info!("[ RESETTING PEACH APT REPO ]");
+    let settings = Settings::default();
+    write_apt_source(&settings)?;
+    write_apt_proxy(&settings)?;
+
+    info!("[ RESTORING CONFIGURATION FILES ]");
+    for file in RESET_FILES {
+        file.install()?;
+    }
+    cmd(&["ln", "-sf", NGINX_SITE.path, NGINX_SITE_LINK])?;
+    cmd(&["systemctl", "daemon-reload"])?;
+
+    println!("PeachCloud configuration has been reset, please reboot your device");
+    Ok(())
+}
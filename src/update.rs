@@ -1,14 +1,39 @@
+use crate::apt::{candidate_versions, changelog, install, list_upgradable, PackageSpec};
+use crate::bundle::install_bundle;
 use crate::constants::SERVICES;
-use crate::error::PeachConfigError;
-use crate::utils::{cmd, get_output};
-use crate::UpdateOpts;
+use crate::debian_version::DebianVersion;
+use crate::error::{CmdIoError, PeachConfigError};
+use crate::generate_manifest::get_currently_installed_microservices;
+use crate::health::{print_health_table, run_health_checks};
+use crate::history::{record_history, Trigger};
+use crate::lock::LOCK_PID_ENV;
+use crate::pinning::{pin_service, read_pins, restore_pins, unpin_service, ServiceSpec};
+use crate::rollback::{current_rollback_point, rollback, rollback_to, save_rollback_point};
+use crate::setup_peach_deb::update_package_lists;
+use crate::utils::get_output;
+use crate::{UpdateCommand, UpdateOpts};
+use log::{info, warn, LevelFilter};
 use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::cmp::Ordering;
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::str::FromStr;
 
 /// Parses update subcommand CLI arguments and calls correct methods.
 ///
+/// If services are passed with --service, only those services are updated,
+/// and --pin and --unpin hold services at a version or release them.
+/// The rollback subcommand reinstalls the versions installed before the last update,
+/// and --from installs an offline bundle without network access.
+/// Every update and rollback is recorded in the update history.
+///
 /// If no options are passed, it runs a full update
 /// - first updating peach-config
-/// - and then re-running peach-config to update all the other microservices
+/// - and then re-executing the newly installed peach-config with a resume token
+///   to update all the other microservices
 ///
 /// # Arguments
 ///
@@ -16,68 +41,382 @@ use serde::{Deserialize, Serialize};
 ///
 /// Any error results in a PeachConfigError, otherwise an Ok is returned.
 pub fn update(opts: UpdateOpts) -> Result<(), PeachConfigError> {
-    if opts.self_only {
-        run_update_self()
-    } else if opts.microservices {
-        update_microservices()
+    let auto_rollback = opts.auto_rollback;
+    if let Some(UpdateCommand::Rollback) = opts.command {
+        record_history(Trigger::Rollback, rollback)
+    } else if !opts.pin.is_empty() || !opts.unpin.is_empty() {
+        for service in &opts.unpin {
+            unpin_service(&service.name)?;
+        }
+        if opts.pin.is_empty() {
+            Ok(())
+        } else {
+            record_history(Trigger::Update, || {
+                checked_update(auto_rollback, || pin_services(&opts.pin))
+            })
+        }
+    } else if let Some(from) = &opts.from {
+        record_history(Trigger::Update, || {
+            checked_update(auto_rollback, || {
+                install_bundle(from, opts.keyring.as_deref())
+            })
+        })
+    } else if !opts.services.is_empty() {
+        record_history(Trigger::Update, || {
+            checked_update(auto_rollback, || update_services(&opts.services))
+        })
+    } else if opts.self_only {
+        record_history(Trigger::Update, run_update_self)
+    } else if opts.microservices || opts.resume == Some(ResumePoint::Microservices) {
+        record_history(Trigger::Update, || {
+            checked_update(auto_rollback, update_microservices)
+        })
+    } else if opts.resume == Some(ResumePoint::AutoUpdate) {
+        record_history(Trigger::AutoUpdate, || {
+            checked_update(true, upgrade_microservices)
+        })
     } else if opts.list {
         list_available_updates()
     }
     // otherwise no options were passed, and we do a full update:
     // - first updating peach-config
-    // - and then re-running peach-config to update all the other microservices
+    // - and then re-executing the newly installed peach-config to update all the other
+    //   microservices, which records its own entry in the update history
     else {
-        run_update_self()?;
-        cmd(&["/usr/bin/peach-config", "update", "--microservices"])?;
+        record_history(Trigger::Update, run_update_self)?;
+        let mut args = vec![
+            "update".to_string(),
+            "--resume".to_string(),
+            ResumePoint::Microservices.to_string(),
+        ];
+        if auto_rollback {
+            args.push("--auto-rollback".to_string());
+        }
+        reexec_self(&args)
+    }
+}
+
+/// Point at which a full update is resumed by the newly installed peach-config,
+/// passed to it with the hidden --resume option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResumePoint {
+    // peach-config has been updated, and the other microservices are updated next
+    Microservices,
+    // peach-config has been updated by an automatic update, and the other microservices
+    // are updated next, always rolling back if a health check fails
+    AutoUpdate,
+}
+
+impl FromStr for ResumePoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "microservices" => Ok(ResumePoint::Microservices),
+            "auto-update" => Ok(ResumePoint::AutoUpdate),
+            _ => Err(format!("unknown resume token {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ResumePoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResumePoint::Microservices => write!(f, "microservices"),
+            ResumePoint::AutoUpdate => write!(f, "auto-update"),
+        }
+    }
+}
+
+/// Returns the path of the installed peach-config binary.
+///
+/// This is the path of the running binary, which still exists after apt replaced it with the
+/// new version, unless peach-config is running from a path that apt removed, in which case the
+/// path is looked up in the list of files dpkg installed for the peach-config package.
+pub fn peach_config_binary() -> Result<PathBuf, PeachConfigError> {
+    if let Ok(exe) = env::current_exe() {
+        // the kernel marks the path of a binary which was replaced while running as deleted
+        let exe = PathBuf::from(exe.to_string_lossy().trim_end_matches(" (deleted)"));
+        if exe.exists() {
+            return Ok(exe);
+        }
+    }
+    get_output(&["dpkg", "--listfiles", "peach-config"])?
+        .lines()
+        .find(|path| path.ends_with("/bin/peach-config"))
+        .map(PathBuf::from)
+        .ok_or_else(|| PeachConfigError::CmdError {
+            msg: "no peach-config binary is installed".to_string(),
+            command: "dpkg --listfiles peach-config".to_string(),
+        })
+}
+
+/// Runs the installed peach-config binary with the given arguments, streaming its output
+/// to the output of this process. Verbose logging is passed on if it is enabled,
+/// and the peach-config lock held by this process is passed on to the new process.
+pub fn reexec_self(args: &[String]) -> Result<(), PeachConfigError> {
+    let binary = peach_config_binary()?;
+    let mut command = Command::new(&binary);
+    if log::max_level() >= LevelFilter::Info {
+        command.arg("--verbose");
+    }
+    command.args(args);
+    command.env(LOCK_PID_ENV, process::id().to_string());
+    info!("command: {:?}", command);
+    let status = command.status().context(CmdIoError {
+        command: format!("{:?}", command),
+    })?;
+    if status.success() {
         Ok(())
+    } else {
+        Err(PeachConfigError::CmdError {
+            msg: format!("exited with {}", status),
+            command: format!("{:?}", command),
+        })
     }
 }
 
+/// Records the installed versions and the health of the services, runs the given update and
+/// then checks that no service which was healthy before the update fails its health check.
+/// Services which were already failing do not fail the update, so that a problem which is
+/// unrelated to the update, such as missing hardware, does not cause a rollback.
+/// If the update or a health check fails and auto_rollback is set, the pins of the services are
+/// put back as they were and the previously installed versions are reinstalled. If the rollback
+/// fails too, the returned error names both failures. The previous versions only replace the saved rollback point
+/// once the update succeeded and passed its health checks.
+pub fn checked_update<F>(auto_rollback: bool, run_update: F) -> Result<(), PeachConfigError>
+where
+    F: FnOnce() -> Result<(), PeachConfigError>,
+{
+    let previous = current_rollback_point()?;
+    let previous_pins = read_pins()?;
+    info!("checking the health of the services before updating");
+    let baseline = run_health_checks();
+    let result = run_update().and_then(|_| {
+        let report = run_health_checks();
+        print_health_table(&report);
+        report.ensure_no_regressions(&baseline)
+    });
+    match &result {
+        Ok(_) => save_rollback_point(&previous)?,
+        Err(err) if auto_rollback => {
+            warn!("update failed ({}), rolling back", err);
+            if let Err(rollback_err) =
+                restore_pins(&previous_pins).and_then(|_| rollback_to(&previous))
+            {
+                return Err(PeachConfigError::RollbackError {
+                    msg: format!("{} (after the update failed: {})", rollback_err, err),
+                });
+            }
+        }
+        Err(_) => {
+            let mut versions: Vec<String> = previous
+                .packages
+                .iter()
+                .map(|(name, version)| format!("{}={}", name, version))
+                .collect();
+            versions.sort();
+            warn!(
+                "update failed, the versions installed before the update were: {}",
+                versions.join(", ")
+            );
+            warn!("reinstall them with peach-config update --service <name>=<version>");
+        }
+    }
+    result
+}
+
 /// Updates peach-config using apt-get
 pub fn run_update_self() -> Result<(), PeachConfigError> {
-    cmd(&["apt-get", "update"])?;
-    cmd(&["apt-get", "install", "-y", "peach-config"])?;
-    Ok(())
+    update_package_lists()?;
+    install(&[PackageSpec::new("peach-config")], false)
 }
 
 /// Installs all peach microservices or updates them to the latest version
 /// except for peach-config
 pub fn update_microservices() -> Result<(), PeachConfigError> {
     // update apt
-    cmd(&["apt-get", "update"])?;
+    update_package_lists()?;
     // filter out peach-config from list of services
-    let services_to_update: Vec<&str> = SERVICES
-        .to_vec()
+    let services_to_update: Vec<PackageSpec> = SERVICES
+        .iter()
+        .filter(|&&x| x != "peach-config")
+        .map(|service| PackageSpec::new(service))
+        .collect();
+
+    // install all services,
+    // allowing downgrades so that services pinned at an older version are held there
+    install(&services_to_update, true)
+}
+
+/// Upgrades the installed microservices which have a newer candidate version, except for
+/// peach-config. Services which are not installed are not installed, and services pinned at
+/// an older version are not downgraded, so that an unattended update only moves services forward.
+pub fn upgrade_microservices() -> Result<(), PeachConfigError> {
+    update_package_lists()?;
+    let upgrades: Vec<PackageSpec> = get_available_updates()?
         .into_iter()
-        .filter(|&x| x != "peach-config")
+        .filter(|update| update.kind == UpdateKind::Upgrade && update.name != "peach-config")
+        .map(|update| PackageSpec::new(&update.name))
         .collect();
+    install(&upgrades, false)
+}
 
-    // apt-get install all services
-    let mut update_cmd = ["apt-get", "install", "-y"].to_vec();
-    update_cmd.extend(services_to_update);
-    cmd(&update_cmd)?;
-    Ok(())
+/// Updates only the given services, each to the given version if one is supplied
+/// and otherwise to the latest (or pinned) version
+pub fn update_services(services: &[ServiceSpec]) -> Result<(), PeachConfigError> {
+    update_package_lists()?;
+    let specs: Vec<PackageSpec> = services.iter().map(PackageSpec::from).collect();
+    install(&specs, true)
+}
+
+/// Pins each of the given services at the given version, or at the installed version
+/// if none is supplied, and installs the pinned version
+pub fn pin_services(services: &[ServiceSpec]) -> Result<(), PeachConfigError> {
+    if services.is_empty() {
+        return Ok(());
+    }
+    let installed = get_currently_installed_microservices()?;
+    let mut pinned = Vec::new();
+    for service in services {
+        let version = match (&service.version, installed.get(&service.name)) {
+            (Some(version), _) | (None, Some(version)) => version.clone(),
+            (None, None) => {
+                return Err(PeachConfigError::CmdError {
+                    msg: format!(
+                        "{} is not installed, a version to pin must be given e.g. {}=1.0.0",
+                        service.name, service.name
+                    ),
+                    command: "peach-config update --pin".to_string(),
+                })
+            }
+        };
+        pin_service(&service.name, &version)?;
+        pinned.push(ServiceSpec {
+            name: service.name.clone(),
+            version: Some(version),
+        });
+    }
+    update_services(&pinned)
+}
+
+/// Whether installing the candidate version of a service would upgrade or downgrade it
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateKind {
+    // the service is not installed, and the candidate version would be installed
+    Install,
+    Upgrade,
+    Downgrade,
+    // the candidate version is already installed
+    Current,
+}
+
+/// Available update for a single service
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceUpdate {
+    pub name: String,
+    // version currently installed, according to dpkg
+    pub installed: Option<String>,
+    // version apt would install, according to apt-cache policy
+    pub candidate: Option<String>,
+    pub kind: UpdateKind,
+    // changes in the candidate version, from the first entry of its Debian changelog
+    pub changelog: Option<String>,
 }
 
 /// Output form of list_available_updates
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ListAvailableUpdatesOutput {
-    // packages is a list of package names
+    // upgradeable is a list of the lines of `apt list --upgradable` for services with a newer version
     upgradeable: Vec<String>,
+    // updates lists every service whose candidate version differs from the installed version
+    updates: Vec<ServiceUpdate>,
+}
+
+/// Returns the first entry of the Debian changelog of the given package version,
+/// or None if the changelog could not be retrieved
+fn changelog_summary(name: &str, version: &str) -> Option<String> {
+    let changelog = changelog(&PackageSpec::at(name, version)).ok()?;
+    // entries end with a trailer line of the form " -- Maintainer <email>  Date"
+    let entry: Vec<&str> = changelog
+        .lines()
+        .take_while(|line| !line.starts_with(" -- "))
+        .collect();
+    let entry = entry.join("\n").trim().to_string();
+    if entry.is_empty() {
+        None
+    } else {
+        Some(entry)
+    }
+}
+
+/// Compares the installed and candidate versions of every service
+/// and returns the services whose candidate version differs from the installed version
+pub fn get_available_updates() -> Result<Vec<ServiceUpdate>, PeachConfigError> {
+    let installed = get_currently_installed_microservices()?;
+    let candidates = candidate_versions(&SERVICES)?;
+
+    let mut updates = Vec::new();
+    for service in SERVICES.iter() {
+        let installed = installed.get(*service).cloned();
+        let candidate = candidates.get(*service).cloned();
+        let kind = match (&installed, &candidate) {
+            (_, None) => continue,
+            (None, Some(_)) => UpdateKind::Install,
+            (Some(installed), Some(candidate)) => {
+                let installed: DebianVersion =
+                    installed
+                        .parse()
+                        .map_err(|msg| PeachConfigError::VersionParseError {
+                            msg,
+                            package: service.to_string(),
+                        })?;
+                let candidate: DebianVersion =
+                    candidate
+                        .parse()
+                        .map_err(|msg| PeachConfigError::VersionParseError {
+                            msg,
+                            package: service.to_string(),
+                        })?;
+                match candidate.cmp(&installed) {
+                    Ordering::Greater => UpdateKind::Upgrade,
+                    Ordering::Less => UpdateKind::Downgrade,
+                    Ordering::Equal => UpdateKind::Current,
+                }
+            }
+        };
+        if kind == UpdateKind::Current {
+            continue;
+        }
+        let changelog = match (&kind, &candidate) {
+            (UpdateKind::Upgrade, Some(candidate)) => changelog_summary(service, candidate),
+            _ => None,
+        };
+        updates.push(ServiceUpdate {
+            name: service.to_string(),
+            installed,
+            candidate,
+            kind,
+            changelog,
+        });
+    }
+    Ok(updates)
 }
 
 /// Checks if there are any PeachCloud updates available and displays them
 pub fn list_available_updates() -> Result<(), PeachConfigError> {
-    cmd(&["apt-get", "update"])?;
-    let output = get_output(&["apt", "list", "--upgradable"])?;
-    let lines = output.split('\n');
-    // filter down to just lines which are one of the services
-    let upgradeable: Vec<String> = lines
+    update_package_lists()?;
+    let updates = get_available_updates()?;
+    let upgradeable = list_upgradable()?
         .into_iter()
-        .filter(|x| SERVICES.iter().any(|s| x.contains(s)))
-        .map(|x| x.to_string())
+        .filter(|package| SERVICES.contains(&package.name.as_str()))
+        .map(|package| package.line)
         .collect();
-    let list_available_updates_output = ListAvailableUpdatesOutput { upgradeable };
+    let list_available_updates_output = ListAvailableUpdatesOutput {
+        upgradeable,
+        updates,
+    };
     let output = serde_json::to_string(&list_available_updates_output)?;
     println!("{}", output);
     Ok(())
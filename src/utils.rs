@@ -1,6 +1,9 @@
-use log::{debug, info};
+use log::{debug, info, warn};
 use snafu::ResultExt;
+use std::fs;
+use std::io::{self, Write};
 use std::process::{Command, Output};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::constants::CONF;
 use crate::error::PeachConfigError;
@@ -14,13 +17,25 @@ use crate::error::{CmdIoError, CmdParseOutputError};
 ///
 /// Returns a std::process::Output if successful and a PeachConfigError otherwise.
 pub fn cmd(args: &[&str]) -> Result<Output, PeachConfigError> {
+    cmd_with_env(args, &[], None)
+}
+
+/// Calls cmd (above) with the given environment variables set for the command,
+/// and in the given working directory if one is given
+pub fn cmd_with_env(
+    args: &[&str],
+    env: &[(&str, &str)],
+    dir: Option<&str>,
+) -> Result<Output, PeachConfigError> {
     info!("command: {:?}", args);
-    let output = Command::new(args[0])
-        .args(&args[1..args.len()])
-        .output()
-        .context(CmdIoError {
-            command: format!("{:?}", args),
-        })?;
+    let mut command = Command::new(args[0]);
+    command.args(&args[1..args.len()]).envs(env.iter().copied());
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let output = command.output().context(CmdIoError {
+        command: format!("{:?}", args),
+    })?;
     debug!("output: {:?}", output);
     if output.status.success() {
         Ok(output)
@@ -47,6 +62,34 @@ pub fn get_output(args: &[&str]) -> Result<String, PeachConfigError> {
     Ok(std_out)
 }
 
+/// A private temporary directory, which is removed along with its contents when this is dropped
+#[derive(Debug)]
+pub struct TempDir {
+    pub path: String,
+}
+
+impl TempDir {
+    /// Creates a new directory with a random name in the temporary directory,
+    /// which only the current user can access
+    pub fn new() -> Result<TempDir, PeachConfigError> {
+        let path = get_output(&["mktemp", "-d", "-t", "peachcloud.XXXXXXXXXX"])?;
+        Ok(TempDir { path })
+    }
+
+    /// Returns the path of the given file in the directory
+    pub fn join(&self, name: &str) -> String {
+        format!("{}/{}", self.path, name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!("failed to remove {}: {}", self.path, err);
+        }
+    }
+}
+
 /// Takes in a relative path from the conf dir and returns the absolute path to the file
 pub fn conf(path: &str) -> String {
     let full_path = format!("{}/{}", CONF, path);
@@ -89,3 +132,103 @@ pub fn does_user_exist(user: &str) -> Result<bool, PeachConfigError> {
         Ok(false)
     }
 }
+
+/// Returns true if a linux group with the given name exists
+pub fn does_group_exist(group: &str) -> Result<bool, PeachConfigError> {
+    let output = Command::new("getent")
+        .arg("group")
+        .arg(group)
+        .output()
+        .context(CmdIoError {
+            command: format!("getent group {}", group),
+        })?;
+    Ok(output.status.success())
+}
+
+/// Asks a yes or no question on the terminal, returning true only if it was answered yes
+pub fn confirm(question: &str) -> Result<bool, PeachConfigError> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().context(CmdIoError {
+        command: "confirm".to_string(),
+    })?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).context(CmdIoError {
+        command: "confirm".to_string(),
+    })?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Formats a point in time as an RFC 3339 timestamp in UTC e.g. "2021-06-14T09:30:00Z"
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // convert days since the unix epoch to a civil date,
+    // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Returns the current time as an RFC 3339 timestamp in UTC
+pub fn now_timestamp() -> String {
+    format_timestamp(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn timestamp(secs: u64) -> String {
+        format_timestamp(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn unix_epoch() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(timestamp(1582979696), "2020-02-29T12:34:56Z");
+        assert_eq!(timestamp(1583020800), "2020-03-01T00:00:00Z");
+        // 2000 is divisible by 400 and so is a leap year
+        assert_eq!(timestamp(951782400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn century_boundaries() {
+        assert_eq!(timestamp(946684800), "2000-01-01T00:00:00Z");
+        assert_eq!(timestamp(946684799), "1999-12-31T23:59:59Z");
+        assert_eq!(timestamp(4102444799), "2099-12-31T23:59:59Z");
+        // 2100 is divisible by 100 but not by 400 and so is not a leap year
+        assert_eq!(timestamp(4107542399), "2100-02-28T23:59:59Z");
+        assert_eq!(timestamp(4107542400), "2100-03-01T00:00:00Z");
+    }
+
+    #[test]
+    fn recent_timestamp() {
+        assert_eq!(timestamp(1700000000), "2023-11-14T22:13:20Z");
+    }
+}